@@ -29,10 +29,14 @@ async fn handle_event(manager: &mut Manager, event: DispatchEvent) {
     let rest = manager.rest();
     if let DispatchEvent::MessageCreate(msg) = event {
         if msg.content.starts_with("!ping") {
+            let content = match manager.stats().latency() {
+                Some(latency) => format!("pong ({}ms)", latency.as_millis()),
+                None => "pong".to_string(),
+            };
             let _ = rest
                 .create_message(msg.channel_id)
                 .reply(msg.id)
-                .content("pong")
+                .content(&content)
                 .unwrap()
                 .await;
         }
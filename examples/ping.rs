@@ -4,7 +4,7 @@ use discord::{
     Error, API_VERSION,
 };
 use futures::{sink::SinkExt, stream::StreamExt};
-use std::{sync::Arc, time::Duration};
+use std::{sync::Arc, time::Instant};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     net::TcpStream,
@@ -41,22 +41,22 @@ async fn main() -> Result<(), Error> {
     let hello = hello.to_text()?;
     conn.recv_json(hello)?;
 
-    // create heartbeat interval
-    let mut interval = tokio::time::interval(Duration::from_millis(conn.heartbeat_interval()));
-
     loop {
         // reconnect the websocket if requested
         if conn.should_reconnect() {
-            socket = reconnect_socket(socket, &info.url).await?;
+            socket = reconnect_socket(socket, &info.url, &mut conn).await?;
         }
 
         if let Some(code) = conn.failed() {
             return Err(code.into());
         }
 
+        // wake up the context once its heartbeat timer expires
+        let timeout = conn.poll_timeout();
+
         tokio::select! {
-            _ = interval.tick() => {
-                conn.queue_heartbeat();
+            _ = sleep_until(timeout) => {
+                conn.on_timeout(Instant::now());
             }
             ws_msg = socket.next() => {
                 match ws_msg {
@@ -65,7 +65,7 @@ async fn main() -> Result<(), Error> {
                     }
                     _ => {
                         log::info!("an error occured, closing connection and reconnecting");
-                        socket = reconnect_socket(socket, &info.url).await?;
+                        socket = reconnect_socket(socket, &info.url, &mut conn).await?;
                     }
                 }
             }
@@ -105,17 +105,39 @@ async fn handle_ws_message(
     Ok(())
 }
 
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => futures::future::pending().await,
+    }
+}
+
 async fn reconnect_socket<S>(
     mut socket: WebSocketStream<S>,
     url: &str,
+    conn: &mut GatewayContext,
 ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, ws::tungstenite::Error>
 where
     S: AsyncWrite + AsyncRead + Unpin,
 {
     log::info!("reconnecting socket");
     let _ = socket.close(None).await;
-    let (socket, _) = ws::connect_async(url).await?;
-    Ok(socket)
+
+    loop {
+        // wait as long as the context asks for, e.g. to back off after failed attempts
+        if let Some(deadline) = conn.reconnect_not_before() {
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+
+        match ws::connect_async(url).await {
+            Ok((socket, _)) => {
+                conn.socket_connected();
+                return Ok(socket);
+            }
+            Err(e) if conn.connect_failed() => log::info!("could not reconnect: {}", e),
+            Err(e) => return Err(e),
+        }
+    }
 }
 
 async fn handle_event(event: Event, rest: Arc<Client>) {
@@ -128,4 +150,4 @@ async fn handle_event(event: Event, rest: Arc<Client>) {
                 .unwrap();
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,78 @@
+//! Identify queue server shared by the shards of multiple processes
+//!
+//! Usage: `identify-queue <address>`
+//!
+//! The address is either `host:port` or `unix:<path>`. If the bot token is set in the
+//! `DISCORD_TOKEN` environment variable, the queue honours `max_concurrency` and the session
+//! start limit of the bot, otherwise only one shard identifies every 5 seconds. The token is not
+//! accepted as an argument, so that it does not show up in the process list.
+//!
+//! Messages are logged to stderr, the level is set with `RUST_LOG` (`info` by default).
+
+use discord::{manager::identify, proto::IdentifyQueue, Error};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::time::Instant;
+use twilight_http::Client;
+
+const USAGE: &str = "usage: [DISCORD_TOKEN=<token>] identify-queue <address>";
+
+/// Environment variable holding the bot token
+const TOKEN_VAR: &str = "DISCORD_TOKEN";
+
+/// Logs to stderr, the binary does not pull in a logging implementation
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{} {}] {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[tokio::main]
+#[allow(clippy::result_large_err)]
+async fn main() -> Result<(), Error> {
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(LevelFilter::Info);
+    log::set_logger(&StderrLogger).expect("logger is only set once");
+    log::set_max_level(level);
+
+    let mut args = std::env::args().skip(1);
+    // the token is not accepted as a second argument anymore
+    let addr = match (args.next(), args.next()) {
+        (Some(addr), None) if addr != "-h" && addr != "--help" => addr,
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let queue = match std::env::var(TOKEN_VAR)
+        .ok()
+        .filter(|token| !token.is_empty())
+    {
+        Some(token) => {
+            let info = Client::new(token).gateway().authed().await?.model().await;
+            let info = info.map_err(|e| Error::Custom(e.to_string()))?;
+            IdentifyQueue::with_limit(&info.session_start_limit, Instant::now())
+        }
+        None => IdentifyQueue::new(1),
+    };
+
+    log::info!(
+        "identify queue listening on {} (max_concurrency= {}, remaining= {:?})",
+        addr,
+        queue.max_concurrency(),
+        queue.remaining()
+    );
+    identify::serve(&addr, queue).await
+}
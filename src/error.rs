@@ -10,14 +10,50 @@ pub enum Error {
     /// Tungstenite error
     #[cfg(feature = "manager")]
     WebSocketError(tokio_tungstenite::tungstenite::Error),
+    /// I/O error
+    #[cfg(feature = "manager")]
+    IoError(std::io::Error),
+    /// The consumer fell behind and the number of events were dropped
+    #[cfg(feature = "manager")]
+    Lagged(u64),
     /// Reqwest error
     #[cfg(feature = "rest")]
     HttpError(HttpError),
     /// Serde parse error
     #[cfg(feature = "json")]
     ParseError(serde_json::Error),
+    /// ETF decode error
+    #[cfg(feature = "etf")]
+    EtfError(crate::proto::etf::EtfError),
+    /// Received message is not valid UTF-8
+    Utf8Error(std::str::Utf8Error),
+    /// zlib decompression error
+    #[cfg(feature = "zlib")]
+    ZlibError(flate2::DecompressError),
+    /// The zlib stream ended or stopped making progress before the message was inflated
+    #[cfg(feature = "zlib")]
+    InvalidZlibStream,
+    /// zstd decompression error
+    #[cfg(feature = "zstd")]
+    ZstdError(std::io::Error),
     /// Gateway Error
     GatewayClosed(Option<CloseCode>),
+    /// The shard index is not lower than the total number of shards
+    InvalidShardId {
+        /// index of the shard
+        index: u64,
+        /// total number of shards
+        total: u64,
+    },
+    /// The cluster index is not lower than the number of clusters
+    InvalidCluster {
+        /// index of the cluster
+        cluster: u64,
+        /// number of clusters
+        clusters: u64,
+    },
+    /// The shard is not managed by this group
+    UnknownShard(crate::proto::ShardId),
     /// Custom Error
     Custom(String),
 }
@@ -27,11 +63,31 @@ impl Display for Error {
         match self {
             #[cfg(feature = "manager")]
             Error::WebSocketError(err) => Display::fmt(err, f),
+            #[cfg(feature = "manager")]
+            Error::IoError(err) => Display::fmt(err, f),
+            #[cfg(feature = "manager")]
+            Error::Lagged(n) => write!(f, "dropped {} events", n),
             #[cfg(feature = "rest")]
             Error::HttpError(err) => Display::fmt(err, f),
             #[cfg(feature = "json")]
             Error::ParseError(err) => Display::fmt(err, f),
+            #[cfg(feature = "etf")]
+            Error::EtfError(err) => Display::fmt(err, f),
+            Error::Utf8Error(err) => Display::fmt(err, f),
+            #[cfg(feature = "zlib")]
+            Error::ZlibError(err) => Display::fmt(err, f),
+            #[cfg(feature = "zlib")]
+            Error::InvalidZlibStream => f.write_str("invalid zlib stream"),
+            #[cfg(feature = "zstd")]
+            Error::ZstdError(err) => Display::fmt(err, f),
             Error::GatewayClosed(err) => write!(f, "GatewayClosed({:?})", err),
+            Error::InvalidShardId { index, total } => {
+                write!(f, "invalid shard id [{}, {}]", index, total)
+            }
+            Error::InvalidCluster { cluster, clusters } => {
+                write!(f, "invalid cluster {} of {} clusters", cluster, clusters)
+            }
+            Error::UnknownShard(shard) => write!(f, "unknown shard {}", shard),
             Error::Custom(err) => f.write_str(err),
         }
     }
@@ -46,6 +102,26 @@ impl From<serde_json::Error> for Error {
     }
 }
 
+#[cfg(feature = "etf")]
+impl From<crate::proto::etf::EtfError> for Error {
+    fn from(err: crate::proto::etf::EtfError) -> Self {
+        Self::EtfError(err)
+    }
+}
+
+impl From<std::str::Utf8Error> for Error {
+    fn from(err: std::str::Utf8Error) -> Self {
+        Self::Utf8Error(err)
+    }
+}
+
+#[cfg(feature = "zlib")]
+impl From<flate2::DecompressError> for Error {
+    fn from(err: flate2::DecompressError) -> Self {
+        Self::ZlibError(err)
+    }
+}
+
 impl From<CloseCode> for Error {
     fn from(code: CloseCode) -> Self {
         Self::GatewayClosed(Some(code))
@@ -59,6 +135,13 @@ impl From<tokio_tungstenite::tungstenite::Error> for Error {
     }
 }
 
+#[cfg(feature = "manager")]
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
+
 #[cfg(feature = "rest")]
 impl From<HttpError> for Error {
     fn from(err: HttpError) -> Self {
@@ -100,10 +183,10 @@ impl From<u16> for CloseCode {
         match v {
             4000 => CloseCode::UnknownError,
             4001 => CloseCode::UnknownOpcode,
-            4003 => CloseCode::DecodeError,
-            4004 => CloseCode::NotAuthenticated,
-            4005 => CloseCode::AuthenticationFailed,
-            4006 => CloseCode::AlreadyAuthenticated,
+            4002 => CloseCode::DecodeError,
+            4003 => CloseCode::NotAuthenticated,
+            4004 => CloseCode::AuthenticationFailed,
+            4005 => CloseCode::AlreadyAuthenticated,
             4007 => CloseCode::InvalidSeq,
             4008 => CloseCode::RateLimited,
             4009 => CloseCode::SessionTimedOut,
@@ -117,24 +200,42 @@ impl From<u16> for CloseCode {
     }
 }
 
+/// How the client should react to a close code
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum CloseAction {
+    /// Reconnect and resume the session
+    Resume,
+    /// Reconnect and identify with a new session
+    Reidentify,
+    /// Do not reconnect
+    Fatal,
+}
+
 impl CloseCode {
-    /// Returns true if the connection can be recovered after receiving this close code
+    /// Returns how the connection should be recovered after receiving this close code
     ///
     /// <https://discord.com/developers/docs/topics/opcodes-and-status-codes#gateway-gateway-close-event-codes>
-    pub fn is_recoverable(&self) -> bool {
+    pub fn action(&self) -> CloseAction {
         match self {
             CloseCode::UnknownError
             | CloseCode::UnknownOpcode
             | CloseCode::DecodeError
             | CloseCode::NotAuthenticated
             | CloseCode::AlreadyAuthenticated
-            | CloseCode::InvalidSeq
-            | CloseCode::RateLimited
-            | CloseCode::SessionTimedOut => true,
-            CloseCode::Other(code) => *code < 4000, // try to recover if the code was not a 4000 code
-            _ => false,
+            | CloseCode::RateLimited => CloseAction::Resume,
+            CloseCode::InvalidSeq | CloseCode::SessionTimedOut => CloseAction::Reidentify,
+            // try to recover if the code was not a 4000 code
+            CloseCode::Other(code) if *code < 4000 => CloseAction::Resume,
+            _ => CloseAction::Fatal,
         }
     }
+
+    /// Returns true if the connection can be recovered after receiving this close code
+    ///
+    /// <https://discord.com/developers/docs/topics/opcodes-and-status-codes#gateway-gateway-close-event-codes>
+    pub fn is_recoverable(&self) -> bool {
+        self.action() != CloseAction::Fatal
+    }
 }
 
 #[derive(Debug)]
@@ -234,4 +335,36 @@ mod tests {
             assert!(code.is_recoverable());
         }
     }
+
+    #[test]
+    fn close_code_action() {
+        use CloseAction::*;
+
+        let codes = [
+            (1000, CloseCode::Other(1000), Resume),
+            (1001, CloseCode::Other(1001), Resume),
+            (4000, CloseCode::UnknownError, Resume),
+            (4001, CloseCode::UnknownOpcode, Resume),
+            (4002, CloseCode::DecodeError, Resume),
+            (4003, CloseCode::NotAuthenticated, Resume),
+            (4004, CloseCode::AuthenticationFailed, Fatal),
+            (4005, CloseCode::AlreadyAuthenticated, Resume),
+            (4006, CloseCode::Other(4006), Fatal),
+            (4007, CloseCode::InvalidSeq, Reidentify),
+            (4008, CloseCode::RateLimited, Resume),
+            (4009, CloseCode::SessionTimedOut, Reidentify),
+            (4010, CloseCode::InvalidShard, Fatal),
+            (4011, CloseCode::ShardingRequired, Fatal),
+            (4012, CloseCode::InvalidAPIVersion, Fatal),
+            (4013, CloseCode::InvalidIntents, Fatal),
+            (4014, CloseCode::DisallowedIntents, Fatal),
+            (4015, CloseCode::Other(4015), Fatal),
+        ];
+
+        for (raw, code, action) in codes {
+            assert_eq!(code, CloseCode::from(raw), "close code {}", raw);
+            assert_eq!(action, code.action(), "close code {}", raw);
+            assert_eq!(action != Fatal, code.is_recoverable(), "close code {}", raw);
+        }
+    }
 }
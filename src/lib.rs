@@ -6,6 +6,8 @@
     missing_debug_implementations,
     rustdoc::broken_intra_doc_links
 )]
+// the websocket and http errors are kept unboxed in `Error`
+#![allow(clippy::result_large_err)]
 
 #[allow(dead_code)]
 pub(crate) const LIB_NAME: &str = "discord-api";
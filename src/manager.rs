@@ -20,12 +20,25 @@
 
 use crate::{
     model::gateway::event::Event,
-    proto::{Config, GatewayContext},
-    Error, API_VERSION,
+    proto::{
+        Config, Encoding, GatewayCommand, GatewayContext, IdentifyQueue, SessionState, State,
+        Stats, CLOSE_ABNORMAL,
+    },
+    Error,
 };
-use futures::{sink::SinkExt, stream::StreamExt};
-use std::{fmt::Debug, ops::Deref, sync::Arc, time::Duration};
-use tokio::{net::TcpStream, time::Interval};
+use futures::{
+    future::{self, BoxFuture},
+    sink::SinkExt,
+    stream::StreamExt,
+    Future,
+};
+use std::{
+    fmt::Debug,
+    ops::Deref,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpStream, sync::mpsc};
 use tokio_tungstenite::{self as ws, WebSocketStream};
 use twilight_http::Client;
 use ws::{
@@ -35,9 +48,25 @@ use ws::{
 
 pub use twilight_http as http;
 
+/// Time to wait for the gateway to acknowledge a close frame
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+mod background;
+pub mod cluster;
+mod command;
+pub mod identify;
+mod stream;
+
+pub use background::{BackgroundManager, Backpressure};
+pub use command::CommandSender;
+use identify::IdentifyClient;
+pub use stream::EventStream;
+
 /// Connect to the discord gateway.
 ///
-/// It is expected from the client that it starts heartbeating right after connecting.
+/// It is expected from the client that it starts heartbeating after connecting.
+/// The first heartbeat is delayed by the jitter determined by the [`GatewayContext`]
+/// (see [`first_heartbeat_delay()`]).
 /// The manager sends heartbeats automatically as long as it is awaiting an event via the
 /// [`recv()`] method.
 ///
@@ -48,42 +77,126 @@ pub use twilight_http as http;
 ///
 /// It is recommended to receive events in a loop and spawn a new thread/task for any
 /// event processing so that the manager will not be blocked from sending heartbeats.
+/// Alternatively the manager can heartbeat in a background task (see [`spawn()`]).
 ///
 /// # Example
 /// See [module docs][self]
 ///
 /// [`recv()`]: Manager::recv
+/// [`first_heartbeat_delay()`]: GatewayContext::first_heartbeat_delay
+/// [`spawn()`]: Manager::spawn
 pub async fn connect(config: Config) -> Result<Manager, Error> {
-    let token = config.token.clone();
-    let rest = Client::new(token.clone());
-    let mut ctx = GatewayContext::new(config.clone());
-
-    let info = {
-        let mut info = rest.gateway().authed().await?.model().await.unwrap();
-        info.url.push_str("/?v=");
-        info.url.push_str(&API_VERSION.to_string());
-        info
-    };
+    let ctx = GatewayContext::new(config.clone());
+    start(config, ctx).await
+}
 
-    log::debug!("BotConnectionInfo= {:?}", &info);
+/// Resume an existing session, e.g. after restarting the process.
+///
+/// The session has to be resumed within the resume window, otherwise the gateway invalidates the
+/// session and the manager identifies with a new session.
+///
+/// # Example
+/// ```no_run
+/// # use discord::{proto::*, model::gateway::Intents};
+/// # async fn run(session: SessionState) -> Result<(), discord::Error> {
+/// # let token = "";
+/// let config = Config::new(token, Intents::all());
+/// let mut manager = discord::manager::resume(config, session).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn resume(config: Config, session: SessionState) -> Result<Manager, Error> {
+    let ctx = GatewayContext::resume(config.clone(), session);
+    start(config, ctx).await
+}
 
-    let (mut socket, _) = ws::connect_async(&info.url).await.unwrap();
+async fn start(config: Config, ctx: GatewayContext) -> Result<Manager, Error> {
+    let rest = Arc::new(Client::new(config.token.clone()));
+    let info = GatewayInfo::fetch(&rest, &config).await?;
+    open(config, ctx, rest, &info.url, Arc::new(info.identify_queue)).await
+}
 
-    // init connection
-    let hello = socket.next().await.unwrap()?;
-    let hello = hello.to_text()?;
-    ctx.recv_json(hello).unwrap();
+/// Information about the gateway returned by `/gateway/bot`
+struct GatewayInfo {
+    /// url of the gateway
+    url: String,
+    /// recommended number of shards
+    shards: u64,
+    /// queue honouring the session start limit of the bot
+    identify_queue: IdentifyQueue,
+}
 
-    let interval = tokio::time::interval(Duration::from_millis(ctx.heartbeat_interval()));
+impl GatewayInfo {
+    /// Requests the gateway information unless the config sets the gateway url
+    async fn fetch(rest: &Client, config: &Config) -> Result<Self, Error> {
+        let info = match &config.gateway_url {
+            Some(gateway_url) => GatewayInfo {
+                url: gateway_url.clone(),
+                shards: config.shard.total(),
+                identify_queue: IdentifyQueue::new(1),
+            },
+            None => {
+                let info = rest.gateway().authed().await?.model().await;
+                let info = info.map_err(|e| Error::Custom(e.to_string()))?;
+                log::debug!("BotConnectionInfo= {:?}", &info);
+                GatewayInfo {
+                    identify_queue: IdentifyQueue::with_limit(
+                        &info.session_start_limit,
+                        Instant::now(),
+                    ),
+                    url: info.url,
+                    shards: info.shards,
+                }
+            }
+        };
+        Ok(info)
+    }
+}
+
+/// Connects the context to the gateway and waits for `Hello`
+async fn open(
+    config: Config,
+    mut ctx: GatewayContext,
+    rest: Arc<Client>,
+    base_url: &str,
+    identify_client: Arc<dyn IdentifyClient>,
+) -> Result<Manager, Error> {
+    // identifies are sent once the identify client allows it
+    ctx.gate_identify(true);
+    ctx.queue_events(true);
+
+    let url = config.connection_url(base_url);
+    let (socket, _) = ws::connect_async(&connect_url(&ctx, &url)).await?;
+    ctx.socket_connected();
 
-    Ok(Manager {
+    let (command_tx, commands) = mpsc::channel(command::COMMAND_BUFFER);
+    let mut manager = Manager {
         ctx,
         socket,
-        rest: Arc::new(rest),
+        rest,
         config,
-        url: info.url,
-        interval,
-    })
+        url,
+        identify_client,
+        identify: None,
+        commands,
+        command_tx,
+    };
+
+    // init connection
+    while manager.ctx.heartbeat_interval() == 0 {
+        let msg = manager
+            .socket
+            .next()
+            .await
+            .ok_or(Error::GatewayClosed(None))??;
+        manager.handle_ws_message(msg).await?;
+
+        if let Some(code) = manager.ctx.failed() {
+            return Err(code.into());
+        }
+    }
+
+    Ok(manager)
 }
 
 /// Managed connection to the discord gateway
@@ -96,7 +209,10 @@ pub struct Manager {
     rest: Arc<Client>,
     config: Config,
     url: String,
-    interval: Interval,
+    identify_client: Arc<dyn IdentifyClient>,
+    identify: Option<BoxFuture<'static, Result<(), Error>>>,
+    commands: mpsc::Receiver<GatewayCommand>,
+    command_tx: mpsc::Sender<GatewayCommand>,
 }
 
 impl Debug for Manager {
@@ -107,7 +223,7 @@ impl Debug for Manager {
             .field("rest", &self.rest)
             .field("token", &self.config.token)
             .field("url", &self.url)
-            .field("interval", &self.interval)
+            .field("identify_client", &self.identify_client)
             .finish()
     }
 }
@@ -128,86 +244,431 @@ impl Manager {
         &mut self.ctx
     }
 
+    /// Get the statistics of the connection, e.g. the heartbeat latency
+    pub fn stats(&self) -> &Stats {
+        self.ctx.stats()
+    }
+
+    /// Get a handle to send commands from other tasks
+    pub fn command_sender(&self) -> CommandSender {
+        CommandSender {
+            tx: self.command_tx.clone(),
+        }
+    }
+
+    /// Turn the manager into a [`Stream`](futures::Stream) of events
+    ///
+    /// Handles that are needed while consuming the stream, e.g. the [`rest()`] client or a
+    /// [`command_sender()`], have to be cloned beforehand.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use discord::{proto::*, model::gateway::Intents};
+    /// use futures::StreamExt;
+    /// # async fn run(config: Config) -> Result<(), discord::Error> {
+    /// let manager = discord::manager::connect(config).await?;
+    /// let mut events = manager.into_stream();
+    ///
+    /// while let Some(event) = events.next().await {
+    ///     println!("received event: {:?}", event?.kind());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`rest()`]: Manager::rest
+    /// [`command_sender()`]: Manager::command_sender
+    pub fn into_stream(self) -> EventStream {
+        EventStream::new(self)
+    }
+
+    /// Drive the connection in a background task
+    ///
+    /// The task receives messages and sends heartbeats while the events are processed, so that a
+    /// slow consumer does not cause the connection to time out. Up to `capacity` events are
+    /// buffered, `backpressure` decides what happens when the buffer is full.
+    pub fn spawn(self, capacity: usize, backpressure: Backpressure) -> BackgroundManager {
+        BackgroundManager::new(self, capacity, backpressure)
+    }
+
+    /// Close the connection and end the session
+    ///
+    /// The socket is closed with code 1000, so that the bot appears offline right away.
+    pub async fn shutdown(mut self) -> Result<(), Error> {
+        self.close(false).await
+    }
+
+    /// Close the connection but keep the session resumable
+    ///
+    /// Returns the state to continue the session with [`resume()`] within the resume window,
+    /// e.g. in the next process of a rolling deploy. Returns `None` if no session was established
+    /// yet.
+    pub async fn close_resumable(mut self) -> Result<Option<SessionState>, Error> {
+        let session = self.ctx.session();
+        self.close(true).await?;
+        Ok(session)
+    }
+
+    async fn close(&mut self, resumable: bool) -> Result<(), Error> {
+        let code = self.ctx.close(resumable);
+        self.commands.close();
+
+        let frame = CloseFrame {
+            code: code.into(),
+            reason: "".into(),
+        };
+        match self.socket.close(Some(frame)).await {
+            Ok(()) => {}
+            Err(
+                ws::tungstenite::Error::ConnectionClosed | ws::tungstenite::Error::AlreadyClosed,
+            ) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+
+        // wait for the gateway to acknowledge the close frame
+        let drain = async { while let Some(Ok(_)) = self.socket.next().await {} };
+        if tokio::time::timeout(CLOSE_TIMEOUT, drain).await.is_err() {
+            log::debug!("gateway did not acknowledge the close frame");
+        }
+        Ok(())
+    }
+
     /// Receive an event from the gateway
+    ///
+    /// Commands of the [`CommandSender`]s are sent while waiting for an event.
     pub async fn recv(&mut self) -> Result<Event, Error> {
         loop {
-            if let Some(code) = self.ctx.failed() {
-                return Err(code.into());
+            self.request_identify();
+            if !self.ctx.should_reconnect() {
+                self.flush().await;
             }
 
-            if self.ctx.should_reconnect() {
-                self.reconnect_socket().await?;
+            if let Some(event) = self.ctx.poll_event() {
+                return Ok(event);
             }
 
-            tokio::select! {
-                _ = self.interval.tick() => {
-                    self.ctx.queue_heartbeat();
-                }
-                ws_msg = self.socket.next() => {
-                    match ws_msg {
-                        Some(Ok(msg)) => {
-                            log::trace!("received websocket message: {:?}", msg);
-                            if let Some(event) = self.handle_ws_message(msg).await? {
-                                return Ok(event);
-                            }
-                        }
-                        Some(Err(e)) => {
-                            log::info!("an error occured while receiving a message: {}", e);
-                            self.reconnect_socket().await?;
-                        }
-                        None => {
-                            log::info!("websocket stream closed...");
-                            self.reconnect_socket().await?;
-                        }
+            self.process(&mut future::pending()).await?;
+        }
+    }
+
+    /// Waits for the next message, timeout, identify permit or command and processes it
+    ///
+    /// Received events are queued in the context. Returns true if `until` completed first.
+    async fn process<F>(&mut self, until: &mut F) -> Result<bool, Error>
+    where
+        F: Future<Output = ()> + Unpin,
+    {
+        if let Some(code) = self.ctx.failed() {
+            self.commands.close();
+            return Err(code.into());
+        }
+
+        if self.ctx.should_reconnect() {
+            self.reconnect_socket().await?;
+        }
+
+        let timeout = self.ctx.poll_timeout();
+
+        tokio::select! {
+            _ = until => return Ok(true),
+            _ = sleep_until(timeout) => {
+                self.ctx.on_timeout(Instant::now());
+            }
+            permit = identify_permit(&mut self.identify) => {
+                self.identify = None;
+                permit?;
+                self.ctx.allow_identify();
+            }
+            Some(cmd) = self.commands.recv() => {
+                self.ctx.enqueue_command(cmd);
+            }
+            ws_msg = self.socket.next() => {
+                match ws_msg {
+                    Some(Ok(msg)) => {
+                        log::trace!("received websocket message: {:?}", msg);
+                        self.handle_ws_message(msg).await?;
+                    }
+                    // the connection is reconnected with backoff on the next call
+                    Some(Err(e)) => {
+                        log::info!("an error occured while receiving a message: {}", e);
+                        self.ctx.recv_close_code(CLOSE_ABNORMAL);
+                    }
+                    None => {
+                        log::info!("websocket stream closed...");
+                        self.ctx.recv_close_code(CLOSE_ABNORMAL);
                     }
                 }
             }
+        }
+        Ok(false)
+    }
 
-            // iterate through all packets generated and send them to the gateway
-            for s in self.ctx.send_iter_json() {
-                log::debug!("sending: {}", s);
-                self.socket
-                    .feed(Message::Text(s))
-                    .await
-                    .expect("could not send");
+    /// Set the client the manager requests its identify permits from
+    ///
+    /// By default the manager uses an [`IdentifyQueue`] created from the session start limit of
+    /// the bot. Managers in multiple processes can share a
+    /// [`RemoteIdentifyQueue`](identify::RemoteIdentifyQueue) instead.
+    pub fn set_identify_client(&mut self, client: Arc<dyn IdentifyClient>) {
+        self.identify_client = client;
+        self.identify = None;
+    }
+
+    /// Requests an identify permit if the context waits for one
+    fn request_identify(&mut self) {
+        if !self.ctx.wants_identify() {
+            self.identify = None;
+        } else if self.identify.is_none() {
+            self.identify = Some(self.identify_client.acquire(self.config.shard));
+        }
+    }
+
+    /// Keeps the connection alive without delivering events until `until` completes, e.g. while
+    /// the consumer is busy
+    ///
+    /// Messages are received and heartbeats are sent as in [`recv()`](Manager::recv), but the
+    /// events stay queued in the context.
+    async fn keep_alive<F>(&mut self, until: F) -> Result<(), Error>
+    where
+        F: Future<Output = ()>,
+    {
+        tokio::pin!(until);
+        loop {
+            self.request_identify();
+            if !self.ctx.should_reconnect() {
+                self.flush().await;
+            }
+            if self.process(&mut until).await? {
+                return Ok(());
             }
-            self.socket.flush().await?;
         }
     }
 
-    async fn handle_ws_message(
-        &mut self,
-        msg: ws::tungstenite::Message,
-    ) -> Result<Option<Event>, Error> {
-        Ok(match msg {
+    /// Sends all generated commands to the gateway
+    ///
+    /// The socket is treated as lost if sending fails, so that it is reconnected.
+    async fn flush(&mut self) {
+        if let Err(e) = self.send_messages().await {
+            log::info!("an error occured while sending a message: {}", e);
+            self.ctx.recv_close_code(CLOSE_ABNORMAL);
+        }
+    }
+
+    async fn send_messages(&mut self) -> Result<(), ws::tungstenite::Error> {
+        while let Some(msg) = self.next_message() {
+            log::debug!("sending: {:?}", msg);
+            self.socket.feed(msg).await?;
+        }
+        self.socket.flush().await
+    }
+
+    /// Encodes the next command in the configured encoding
+    fn next_message(&mut self) -> Option<Message> {
+        match self.config.encoding {
+            Encoding::Json => self.ctx.send_json().map(Message::Text),
+            #[cfg(feature = "etf")]
+            Encoding::Etf => self.ctx.send_etf().map(Message::Binary),
+        }
+    }
+
+    async fn handle_ws_message(&mut self, msg: ws::tungstenite::Message) -> Result<(), Error> {
+        match msg {
             Message::Close(Some(CloseFrame { code, reason })) => {
                 log::info!("conn closed: code= {} reason= {}", code, reason);
                 self.ctx.recv_close_code(code);
-                None
-            }
-            Message::Text(msg) => {
-                let event = self.ctx.recv_json(&msg)?;
-                Some(Event::from(event))
             }
+            Message::Text(msg) => self.ctx.recv_json_event(&msg)?,
+            Message::Binary(msg) => self.ctx.recv_binary_event(&msg)?,
             msg => {
                 log::info!("ignoring unexpected message: {:?}", msg);
-                None
             }
-        })
+        }
+        Ok(())
     }
 
     async fn reconnect_socket(&mut self) -> Result<(), ws::tungstenite::Error> {
         log::debug!("reconnecting socket");
         let _ = self.socket.close(None).await;
-        let (socket, _) = ws::connect_async(self.ctx.resume_gateway_url()).await?;
+
+        let url = connect_url(&self.ctx, &self.url);
+
+        let socket = loop {
+            // e.g. the gateway asks to wait before identifying after an invalid session
+            if let Some(deadline) = self.ctx.reconnect_not_before() {
+                log::debug!(
+                    "waiting {:?} before reconnecting",
+                    deadline.saturating_duration_since(Instant::now())
+                );
+                tokio::time::sleep_until(deadline.into()).await;
+            }
+
+            match ws::connect_async(&url).await {
+                Ok((socket, _)) => break socket,
+                Err(e) => {
+                    log::info!("could not reconnect: {}", e);
+                    if !self.ctx.connect_failed() {
+                        return Err(e);
+                    }
+                }
+            }
+        };
         self.socket = socket;
+        self.ctx.socket_connected();
         Ok(())
     }
 }
 
+/// Returns the url the socket should connect to
+///
+/// Sessions are resumed over the resume url of the session, while new sessions are identified
+/// over the url the manager was created with.
+fn connect_url(ctx: &GatewayContext, url: &str) -> String {
+    match (ctx.state(), ctx.resume_gateway_url()) {
+        // the context resumes the session after receiving `Hello` in these states
+        (State::Resume | State::Ready, resume_url) if !resume_url.is_empty() => {
+            ctx.config.connection_url(resume_url)
+        }
+        _ => url.to_string(),
+    }
+}
+
+/// Waits for the requested identify permit or forever if none was requested
+async fn identify_permit(
+    identify: &mut Option<BoxFuture<'static, Result<(), Error>>>,
+) -> Result<(), Error> {
+    match identify {
+        Some(identify) => identify.await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Sleeps until the deadline is reached or forever if there is none
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => futures::future::pending().await,
+    }
+}
+
 impl Deref for Manager {
     type Target = Arc<Client>;
     fn deref(&self) -> &<Self as Deref>::Target {
         self.rest()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::CloseCode, model::gateway::Intents, proto::BackoffPolicy};
+    use tokio::{io::AsyncWriteExt, net::TcpListener, sync::mpsc::UnboundedReceiver};
+
+    /// Allows every identify right away
+    #[derive(Debug)]
+    struct Unlimited;
+
+    impl IdentifyClient for Unlimited {
+        fn acquire(&self, _shard: crate::proto::ShardId) -> BoxFuture<'static, Result<(), Error>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    /// Runs a gateway that reports the number of the connection every identify was received on
+    ///
+    /// Connections are dropped after the identify without sending a close frame if `drop` is set.
+    async fn gateway(drop: bool) -> (String, UnboundedReceiver<usize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (tx, identified) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut connections = 0;
+            while let Ok((stream, _)) = listener.accept().await {
+                connections += 1;
+                let connection = connections;
+                let tx = tx.clone();
+
+                tokio::spawn(async move {
+                    let mut socket = ws::accept_async(stream).await.unwrap();
+                    let hello = r#"{"op":10,"d":{"heartbeat_interval":41250}}"#;
+                    socket.send(Message::Text(hello.into())).await.unwrap();
+
+                    while let Some(Ok(msg)) = socket.next().await {
+                        if msg.to_text().is_ok_and(|text| text.contains(r#""op":2"#)) {
+                            let _ = tx.send(connection);
+                            if drop {
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        (url, identified)
+    }
+
+    async fn manager(url: &str, backoff: BackoffPolicy) -> Manager {
+        let config = Config::new("TOKEN", Intents::empty())
+            .gateway_url(url.to_string())
+            .backoff(backoff);
+        let ctx = GatewayContext::new(config.clone());
+        let rest = Arc::new(Client::new(String::new()));
+        open(config, ctx, rest, url, Arc::new(Unlimited))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn connection_lost() {
+        let (url, mut identified) = gateway(true).await;
+        let backoff = BackoffPolicy {
+            initial_delay: Duration::from_millis(10),
+            jitter: false,
+            max_attempts: Some(3),
+            ..Default::default()
+        };
+        let mut manager = manager(&url, backoff).await;
+
+        let error = loop {
+            if let Err(e) = manager.recv().await {
+                break e;
+            }
+        };
+        assert!(matches!(
+            error,
+            Error::GatewayClosed(Some(CloseCode::Other(1006)))
+        ));
+
+        // the initial connection and two reconnects
+        let mut connections = Vec::new();
+        while let Ok(connection) = identified.try_recv() {
+            connections.push(connection);
+        }
+        assert_eq!(vec![1, 2, 3], connections);
+    }
+
+    #[tokio::test]
+    async fn send_failed() {
+        let (url, mut identified) = gateway(false).await;
+        let mut manager = manager(&url, BackoffPolicy::default()).await;
+
+        // the identify can not be sent, which reconnects the socket instead of failing every call
+        match manager.socket.get_mut() {
+            MaybeTlsStream::Plain(stream) => stream.shutdown().await.unwrap(),
+            _ => unreachable!(),
+        }
+
+        let identify = async {
+            loop {
+                tokio::select! {
+                    connection = identified.recv() => break connection,
+                    event = manager.recv() => {
+                        event.unwrap();
+                    }
+                }
+            }
+        };
+        let connection = tokio::time::timeout(Duration::from_secs(5), identify).await;
+        assert_eq!(Some(2), connection.unwrap());
+    }
+}
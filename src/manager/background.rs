@@ -0,0 +1,539 @@
+//! Driving a [`Manager`] in a background task
+//!
+//! The background task receives messages and sends heartbeats independently of the consumer.
+//! Events are delivered over a bounded buffer, the [`Backpressure`] policy decides what happens
+//! when the consumer falls behind.
+
+use super::{CommandSender, Manager};
+use crate::{error::CloseCode, model::gateway::event::Event, proto::SessionState, Error};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+};
+use tokio::{
+    sync::{oneshot, Notify},
+    task::JoinHandle,
+};
+use twilight_http::Client;
+
+/// What the background task does when the event buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Wait until the consumer received an event
+    ///
+    /// Messages are still received and heartbeats sent while waiting, the received events are
+    /// held back in the [`GatewayContext`](crate::proto::GatewayContext) until the consumer
+    /// catches up.
+    Block,
+    /// Drop the oldest buffered event
+    DropOldest,
+    /// Drop the new event and report the number of dropped events as [`Error::Lagged`]
+    Error,
+}
+
+/// Handle to a [`Manager`] running in a background task, created with [`Manager::spawn()`]
+///
+/// Dropping the handle shuts the connection down. Once the connection failed,
+/// [`shutdown()`](BackgroundManager::shutdown) and
+/// [`close_resumable()`](BackgroundManager::close_resumable) return the failure as
+/// [`Error::GatewayClosed`].
+///
+/// # Example
+/// ```no_run
+/// # use discord::{proto::*, model::gateway::Intents};
+/// use discord::manager::Backpressure;
+/// # async fn run(config: Config) -> Result<(), discord::Error> {
+/// let manager = discord::manager::connect(config).await?;
+/// let mut manager = manager.spawn(128, Backpressure::DropOldest);
+///
+/// while let Ok(event) = manager.recv().await {
+///     // slow event handlers do not delay heartbeats
+///     println!("received event: {:?}", event.kind());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct BackgroundManager {
+    buffer: Arc<EventBuffer>,
+    commands: CommandSender,
+    rest: Arc<Client>,
+    shutdown: Option<oneshot::Sender<bool>>,
+    task: JoinHandle<Result<Option<SessionState>, Error>>,
+}
+
+impl BackgroundManager {
+    pub(super) fn new(manager: Manager, capacity: usize, backpressure: Backpressure) -> Self {
+        let buffer = Arc::new(EventBuffer::new(capacity));
+        let (shutdown, shutdown_rx) = oneshot::channel();
+
+        BackgroundManager {
+            commands: manager.command_sender(),
+            rest: Arc::clone(manager.rest()),
+            buffer: Arc::clone(&buffer),
+            shutdown: Some(shutdown),
+            task: tokio::spawn(run(manager, buffer, backpressure, shutdown_rx)),
+        }
+    }
+
+    /// get a reference to the internal rest client
+    pub fn rest(&self) -> &Arc<Client> {
+        &self.rest
+    }
+
+    /// Get a handle to send commands from other tasks
+    pub fn command_sender(&self) -> CommandSender {
+        self.commands.clone()
+    }
+
+    /// Receive the next buffered event
+    ///
+    /// Returns [`Error::GatewayClosed`] once the connection failed and all events were received.
+    pub async fn recv(&mut self) -> Result<Event, Error> {
+        self.buffer
+            .pop()
+            .await
+            .unwrap_or(Err(Error::GatewayClosed(None)))
+    }
+
+    /// Close the connection and end the session, see [`Manager::shutdown()`]
+    pub async fn shutdown(self) -> Result<(), Error> {
+        self.stop(false).await.map(|_| ())
+    }
+
+    /// Close the connection but keep the session resumable, see [`Manager::close_resumable()`]
+    ///
+    /// The sequence of the session includes the events that were received but not delivered yet,
+    /// so they are not replayed after resuming and are returned instead.
+    pub async fn close_resumable(self) -> Result<(Option<SessionState>, Vec<Event>), Error> {
+        let buffer = Arc::clone(&self.buffer);
+        let session = self.stop(true).await?;
+        Ok((session, buffer.take_events()))
+    }
+
+    async fn stop(mut self, resumable: bool) -> Result<Option<SessionState>, Error> {
+        if let Some(shutdown) = self.shutdown.take() {
+            // the task already stopped if the connection failed
+            let _ = shutdown.send(resumable);
+        }
+
+        match (&mut self.task).await {
+            Ok(result) => result,
+            Err(e) => Err(Error::Custom(format!("background task failed: {}", e))),
+        }
+    }
+}
+
+/// Drives the manager until the connection failed or the handle requested a shutdown
+async fn run(
+    mut manager: Manager,
+    buffer: Arc<EventBuffer>,
+    backpressure: Backpressure,
+    mut shutdown: oneshot::Receiver<bool>,
+) -> Result<Option<SessionState>, Error> {
+    let result = loop {
+        tokio::select! {
+            // the handle was dropped if receiving fails
+            resumable = &mut shutdown => {
+                break match resumable {
+                    Ok(true) => {
+                        // hand the events that are held back in the context to the consumer
+                        while let Some(event) = manager.context_mut().poll_event() {
+                            buffer.push(Ok(event));
+                        }
+                        manager.close_resumable().await
+                    }
+                    _ => manager.shutdown().await.map(|_| None),
+                };
+            }
+            failed = forward(&mut manager, &buffer, backpressure) => {
+                if let Some(code) = failed {
+                    break Err(code.into());
+                }
+            }
+        }
+    };
+
+    buffer.close();
+    result
+}
+
+/// Receives the next event and buffers it, returns the close code if the connection failed
+async fn forward(
+    manager: &mut Manager,
+    buffer: &EventBuffer,
+    backpressure: Backpressure,
+) -> Option<CloseCode> {
+    if backpressure == Backpressure::Block {
+        // the events are held back in the context until there is space, the background task is
+        // the only producer, so the space can not be taken away
+        while buffer.is_full() {
+            if let Err(e) = manager.keep_alive(buffer.writable.notified()).await {
+                if manager.context().failed().is_some() {
+                    // the failure is returned by `recv()`
+                    break;
+                }
+                // errors are buffered beyond the capacity, so that they are not held back
+                log::info!("error while waiting for the consumer: {}", e);
+                buffer.push(Err(e));
+            }
+        }
+    }
+
+    let event = manager.recv().await;
+    let failed = match event {
+        Err(_) => manager.context().failed(),
+        Ok(_) => None,
+    };
+
+    match backpressure {
+        Backpressure::Block => buffer.push(event),
+        Backpressure::DropOldest => buffer.push_drop_oldest(event),
+        Backpressure::Error => buffer.push_or_lag(event),
+    }
+
+    failed
+}
+
+/// Bounded buffer between the background task and the consumer
+#[derive(Debug)]
+struct EventBuffer {
+    state: Mutex<BufferState>,
+    capacity: usize,
+    /// notified when an event was buffered or the buffer was closed
+    readable: Notify,
+    /// notified when the consumer received an event
+    writable: Notify,
+}
+
+#[derive(Debug, Default)]
+struct BufferState {
+    events: VecDeque<Result<Event, Error>>,
+    /// number of dropped events that were not reported yet
+    lagged: u64,
+    closed: bool,
+}
+
+impl EventBuffer {
+    fn new(capacity: usize) -> Self {
+        EventBuffer {
+            state: Mutex::default(),
+            capacity: capacity.max(1),
+            readable: Notify::new(),
+            writable: Notify::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BufferState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn is_full(&self) -> bool {
+        self.lock().events.len() >= self.capacity
+    }
+
+    /// Buffers the event even if the buffer is full
+    fn push(&self, event: Result<Event, Error>) {
+        self.lock().events.push_back(event);
+        self.readable.notify_one();
+    }
+
+    fn push_drop_oldest(&self, event: Result<Event, Error>) {
+        let mut state = self.lock();
+        if state.events.len() >= self.capacity {
+            log::warn!("event buffer full, dropping the oldest event");
+            state.events.pop_front();
+        }
+
+        state.events.push_back(event);
+        self.readable.notify_one();
+    }
+
+    fn push_or_lag(&self, event: Result<Event, Error>) {
+        let mut state = self.lock();
+
+        // report dropped events in the order they were dropped
+        if state.lagged > 0 && state.events.len() < self.capacity {
+            let lagged = std::mem::take(&mut state.lagged);
+            state.events.push_back(Err(Error::Lagged(lagged)));
+        }
+
+        if state.events.len() < self.capacity {
+            state.events.push_back(event);
+        } else {
+            log::warn!("event buffer full, dropping event");
+            state.lagged += 1;
+        }
+        self.readable.notify_one();
+    }
+
+    /// Takes all buffered events, errors are dropped
+    fn take_events(&self) -> Vec<Event> {
+        self.lock()
+            .events
+            .drain(..)
+            .filter_map(Result::ok)
+            .collect()
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.readable.notify_one();
+    }
+
+    /// Returns the next event or `None` once the buffer is closed and empty
+    async fn pop(&self) -> Option<Result<Event, Error>> {
+        loop {
+            {
+                let mut state = self.lock();
+                if let Some(event) = state.events.pop_front() {
+                    self.writable.notify_one();
+                    return Some(event);
+                }
+                if state.lagged > 0 {
+                    return Some(Err(Error::Lagged(std::mem::take(&mut state.lagged))));
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+
+            self.readable.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        manager::open,
+        model::gateway::event::EventType,
+        proto::{Config, GatewayContext, IdentifyQueue},
+    };
+    use futures::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+    use tokio::{net::TcpListener, sync::mpsc};
+    use tokio_tungstenite::tungstenite::{protocol::CloseFrame, Message};
+    use twilight_model::gateway::Intents;
+
+    fn event(seq: u64) -> Result<Event, Error> {
+        Ok(Event::GatewayHeartbeat(seq))
+    }
+
+    fn seq(event: Option<Result<Event, Error>>) -> Option<u64> {
+        match event {
+            Some(Ok(Event::GatewayHeartbeat(seq))) => Some(seq),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let buffer = EventBuffer::new(2);
+        for n in 0..4 {
+            buffer.push_drop_oldest(event(n));
+        }
+        buffer.close();
+
+        assert_eq!(Some(2), seq(buffer.pop().await));
+        assert_eq!(Some(3), seq(buffer.pop().await));
+        assert!(buffer.pop().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn lagged() {
+        let buffer = EventBuffer::new(2);
+        for n in 0..5 {
+            buffer.push_or_lag(event(n));
+        }
+        assert!(buffer.is_full());
+
+        assert_eq!(Some(0), seq(buffer.pop().await));
+        assert_eq!(Some(1), seq(buffer.pop().await));
+
+        // the dropped events are reported before newer events
+        buffer.push_or_lag(event(6));
+        assert!(matches!(buffer.pop().await, Some(Err(Error::Lagged(3)))));
+        assert_eq!(Some(6), seq(buffer.pop().await));
+    }
+
+    /// Accepts one connection, identifies it and returns the opcode and data of the received
+    /// payloads
+    async fn gateway(listener: TcpListener) -> mpsc::UnboundedReceiver<(u64, Value)> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let hello = json!({"op": 10, "d": {"heartbeat_interval": 50}});
+            socket.send(Message::Text(hello.to_string())).await.unwrap();
+
+            while let Some(Ok(Message::Text(text))) = socket.next().await {
+                let payload: Value = serde_json::from_str(&text).unwrap();
+                let op = payload["op"].as_u64().unwrap();
+
+                let replies = match op {
+                    1 => vec![json!({"op": 11, "d": null})],
+                    2 => {
+                        let ready = json!({
+                            "v": 10,
+                            "user": {
+                                "id": "1",
+                                "username": "username",
+                                "discriminator": "0000",
+                                "avatar": null,
+                                "bot": true,
+                                "mfa_enabled": false,
+                            },
+                            "guilds": [],
+                            "session_id": "session_id",
+                            "resume_gateway_url": "resume_url",
+                            "shard": [0, 1],
+                            "application": {"id": "1", "flags": 0},
+                        });
+                        let mut replies = vec![json!({"op": 0, "s": 1, "t": "READY", "d": ready})];
+                        replies.extend((2..5).map(|seq| {
+                            let guild = json!({"id": seq.to_string(), "unavailable": true});
+                            json!({"op": 0, "s": seq, "t": "GUILD_DELETE", "d": guild})
+                        }));
+                        // undecodable
+                        replies.push(json!({"op": 0}));
+                        replies
+                    }
+                    _ => Vec::new(),
+                };
+
+                let _ = tx.send((op, payload["d"].clone()));
+                for reply in replies {
+                    socket.send(Message::Text(reply.to_string())).await.unwrap();
+                }
+            }
+        });
+
+        rx
+    }
+
+    async fn connect(url: &str) -> Manager {
+        let config = Config::new("TOKEN", Intents::empty()).gateway_url(url.to_string());
+        let ctx = GatewayContext::new(config.clone());
+        let rest = Arc::new(Client::new(String::new()));
+        let identify = Arc::new(IdentifyQueue::new(1));
+        open(config, ctx, rest, url, identify).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn block_keeps_alive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let mut payloads = gateway(listener).await;
+
+        let manager = connect(&url).await;
+        let mut manager = manager.spawn(1, Backpressure::Block);
+
+        // the consumer does not receive, the heartbeats are still acknowledged and carry the
+        // sequence of the held back events
+        let mut heartbeats = Vec::new();
+        while heartbeats.len() < 5 {
+            let payload = tokio::time::timeout(Duration::from_secs(5), payloads.recv()).await;
+            match payload.unwrap().unwrap() {
+                (1, seq) => heartbeats.push(seq),
+                (2, _) => {}
+                (op, _) => panic!("unexpected opcode {}", op),
+            }
+        }
+        assert_eq!(Some(&json!(4)), heartbeats.last());
+
+        // the held back dispatches are delivered in order, the error is not dropped
+        let mut dispatches = Vec::new();
+        let mut errors = 0;
+        while dispatches.len() < 4 {
+            match manager.recv().await {
+                Ok(event @ (Event::Ready(_) | Event::GuildDelete(_))) => {
+                    dispatches.push(event.kind())
+                }
+                Ok(_) => {}
+                Err(Error::ParseError(_)) => errors += 1,
+                Err(e) => panic!("unexpected error {}", e),
+            }
+        }
+        assert_eq!(1, errors);
+        assert_eq!(
+            vec![
+                EventType::Ready,
+                EventType::GuildDelete,
+                EventType::GuildDelete,
+                EventType::GuildDelete
+            ],
+            dispatches
+        );
+        manager.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stop_failed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let hello = json!({"op": 10, "d": {"heartbeat_interval": 41250}});
+            socket.send(Message::Text(hello.to_string())).await.unwrap();
+
+            // reject the identify
+            let _identify = socket.next().await;
+            let frame = CloseFrame {
+                code: 4004.into(),
+                reason: "Authentication failed.".into(),
+            };
+            let _ = socket.close(Some(frame)).await;
+        });
+
+        let manager = connect(&url).await;
+        let mut manager = manager.spawn(8, Backpressure::DropOldest);
+
+        while manager.recv().await.is_ok() {}
+        assert!(matches!(
+            manager.shutdown().await,
+            Err(Error::GatewayClosed(Some(CloseCode::AuthenticationFailed)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn close_buffered() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let mut payloads = gateway(listener).await;
+
+        let manager = connect(&url).await;
+        let manager = manager.spawn(1, Backpressure::Block);
+
+        // wait until all dispatches were received
+        loop {
+            let payload = tokio::time::timeout(Duration::from_secs(5), payloads.recv()).await;
+            if payload.unwrap().unwrap() == (1, json!(4)) {
+                break;
+            }
+        }
+
+        // none of the events were delivered, so they are returned with the session
+        let (session, events) = manager.close_resumable().await.unwrap();
+        assert_eq!(4, session.unwrap().seq);
+        let dispatches: Vec<_> = events
+            .iter()
+            .filter(|event| matches!(event, Event::Ready(_) | Event::GuildDelete(_)))
+            .map(Event::kind)
+            .collect();
+        assert_eq!(
+            vec![
+                EventType::Ready,
+                EventType::GuildDelete,
+                EventType::GuildDelete,
+                EventType::GuildDelete
+            ],
+            dispatches
+        );
+    }
+}
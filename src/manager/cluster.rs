@@ -0,0 +1,261 @@
+//! Managed connections of multiple shards
+//!
+//! A [`ClusterManager`] runs one [`Manager`] per shard in a background task. The shards share one
+//! identify client, so they identify according to the `max_concurrency` of the bot, and their
+//! events are merged into a single stream tagged with the shard they were received on.
+//!
+//! The merged stream buffers a limited number of events. Shards wait for the consumer once the
+//! buffer is full, so a consumer that falls behind for longer than a heartbeat interval causes
+//! the shards to resume.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use discord::{proto::*, model::gateway::Intents};
+//! # async fn run() -> Result<(), discord::Error> {
+//! # let token = "";
+//! let config = Config::new(token, Intents::all());
+//! let mut cluster = discord::manager::cluster::connect(config).await?;
+//!
+//! while let Some((shard, event)) = cluster.recv().await {
+//!     match event {
+//!         Ok(event) => println!("shard {} received event: {:?}", shard, event.kind()),
+//!         Err(e) => println!("shard {} failed: {}", shard, e),
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use super::{identify::IdentifyClient, open, GatewayInfo};
+use crate::{
+    model::gateway::event::Event,
+    proto::{Config, GatewayContext, ShardId},
+    Error,
+};
+use futures::{Stream, StreamExt};
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    sync::mpsc::{self, Receiver, Sender},
+    task::JoinHandle,
+};
+use twilight_http::Client;
+
+/// Number of events of all shards that are buffered until they are received
+const EVENT_BUFFER: usize = 256;
+
+/// Connect all shards recommended by `/gateway/bot`
+///
+/// The shard count is taken from the total of [`Config::shard`] instead if
+/// [`Config::gateway_url`] is set.
+pub async fn connect(config: Config) -> Result<ClusterManager, Error> {
+    let rest = Arc::new(Client::new(config.token.clone()));
+    let info = GatewayInfo::fetch(&rest, &config).await?;
+    let shards = ShardId::range(0..info.shards, info.shards)?;
+    Ok(spawn(config, shards, rest, info))
+}
+
+/// Connect the given shards, e.g. the shards of one cluster created with [`ShardId::cluster`]
+pub async fn connect_shards(config: Config, shards: Vec<ShardId>) -> Result<ClusterManager, Error> {
+    let rest = Arc::new(Client::new(config.token.clone()));
+    let info = GatewayInfo::fetch(&rest, &config).await?;
+    Ok(spawn(config, shards, rest, info))
+}
+
+fn spawn(
+    config: Config,
+    shards: Vec<ShardId>,
+    rest: Arc<Client>,
+    info: GatewayInfo,
+) -> ClusterManager {
+    let (tx, events) = mpsc::channel(EVENT_BUFFER);
+    let GatewayInfo {
+        url,
+        identify_queue,
+        ..
+    } = info;
+    let identify_client: Arc<dyn IdentifyClient> = Arc::new(identify_queue);
+
+    let tasks = shards
+        .iter()
+        .map(|&shard| {
+            let config = config.clone().shard(shard);
+            let ctx = GatewayContext::new(config.clone());
+            let rest = Arc::clone(&rest);
+            let url = url.clone();
+            let identify_client = Arc::clone(&identify_client);
+            let tx = tx.clone();
+
+            tokio::spawn(async move {
+                match open(config, ctx, rest, &url, identify_client).await {
+                    Ok(manager) => forward(shard, manager.into_stream(), tx).await,
+                    Err(e) => {
+                        let _ = tx.send((shard, Err(e))).await;
+                    }
+                }
+            })
+        })
+        .collect();
+
+    ClusterManager {
+        shards,
+        rest,
+        events,
+        tasks,
+    }
+}
+
+/// Forwards the events of one shard until the cluster was dropped or the stream ended, which
+/// the [`EventStream`](super::EventStream) of a manager does once the connection failed
+async fn forward<S>(shard: ShardId, mut events: S, tx: Sender<(ShardId, Result<Event, Error>)>)
+where
+    S: Stream<Item = Result<Event, Error>> + Unpin,
+{
+    while let Some(event) = events.next().await {
+        if let Err(e) = &event {
+            log::info!("shard {} received an error: {}", shard, e);
+        }
+
+        if tx.send((shard, event)).await.is_err() {
+            break;
+        }
+    }
+    log::debug!("shard {} stopped", shard);
+}
+
+/// Managed connections of multiple shards
+///
+/// The events are received with [`recv()`](ClusterManager::recv) or by using the cluster as a
+/// [`Stream`].
+pub struct ClusterManager {
+    shards: Vec<ShardId>,
+    rest: Arc<Client>,
+    events: Receiver<(ShardId, Result<Event, Error>)>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for ClusterManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClusterManager")
+            .field("shards", &self.shards)
+            .field("rest", &self.rest)
+            .finish()
+    }
+}
+
+impl ClusterManager {
+    /// get a reference to the internal rest client
+    pub fn rest(&self) -> &Arc<Client> {
+        &self.rest
+    }
+
+    /// Returns the shards of this cluster
+    pub fn shards(&self) -> &[ShardId] {
+        &self.shards
+    }
+
+    /// Receive the next event of any shard
+    ///
+    /// Errors are tagged with their shard as well. A shard stops after its connection failed,
+    /// `None` is returned once all shards stopped.
+    pub async fn recv(&mut self) -> Option<(ShardId, Result<Event, Error>)> {
+        self.events.recv().await
+    }
+}
+
+impl Stream for ClusterManager {
+    type Item = (ShardId, Result<Event, Error>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+impl Drop for ClusterManager {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::CloseCode;
+    use futures::stream;
+
+    fn cluster(
+        shards: Vec<ShardId>,
+        events: Receiver<(ShardId, Result<Event, Error>)>,
+    ) -> ClusterManager {
+        ClusterManager {
+            shards,
+            rest: Arc::new(Client::new(String::new())),
+            events,
+            tasks: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn merge() {
+        let shards = ShardId::range(0..2, 2).unwrap();
+        let (tx, events) = mpsc::channel(1);
+
+        for &shard in &shards {
+            let events = (0..10).map(|seq| Ok(Event::GatewayHeartbeat(seq)));
+            tokio::spawn(forward(shard, stream::iter(events), tx.clone()));
+        }
+        drop(tx);
+
+        // the shards wait for the consumer while the buffer is full
+        let mut cluster = cluster(shards.clone(), events);
+        let mut received = vec![Vec::new(); shards.len()];
+        while let Some((shard, event)) = cluster.recv().await {
+            match event {
+                Ok(Event::GatewayHeartbeat(seq)) => received[shard.index() as usize].push(seq),
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+
+        // the events of each shard stay in order
+        let expected: Vec<_> = (0..10).collect();
+        assert_eq!(vec![expected.clone(), expected], received);
+    }
+
+    #[tokio::test]
+    async fn shard_stops() {
+        let shard = ShardId::ONE;
+        let (tx, events) = mpsc::channel(EVENT_BUFFER);
+
+        // the stream of a manager ends after the connection failed
+        let failed = Err(Error::GatewayClosed(Some(CloseCode::AuthenticationFailed)));
+        let task = tokio::spawn(forward(shard, stream::iter(vec![failed]), tx.clone()));
+        task.await.unwrap();
+
+        // shards stop once the cluster was dropped
+        let task = tokio::spawn(forward(
+            shard,
+            stream::repeat(Event::GatewayReconnect).map(Ok),
+            tx,
+        ));
+        let mut cluster = cluster(vec![shard], events);
+        assert!(matches!(
+            cluster.recv().await,
+            Some((
+                _,
+                Err(Error::GatewayClosed(Some(CloseCode::AuthenticationFailed)))
+            ))
+        ));
+        assert!(matches!(
+            cluster.recv().await,
+            Some((_, Ok(Event::GatewayReconnect)))
+        ));
+        drop(cluster);
+        task.await.unwrap();
+    }
+}
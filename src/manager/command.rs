@@ -0,0 +1,96 @@
+//! Sending commands from other tasks
+
+use crate::{
+    model::gateway::payload::outgoing::{RequestGuildMembers, UpdatePresence, UpdateVoiceState},
+    proto::GatewayCommand,
+    Error,
+};
+use tokio::sync::mpsc;
+
+/// Number of commands that can be queued before [`CommandSender`] waits for the manager
+pub(super) const COMMAND_BUFFER: usize = 32;
+
+/// Cloneable handle to send commands over the connection of a [`Manager`](super::Manager)
+///
+/// Commands are queued in the [`GatewayContext`](crate::proto::GatewayContext) and sent, subject
+/// to the rate limit, as soon as the session is ready and the manager is receiving events via
+/// [`recv()`](super::Manager::recv). Sending fails once the connection failed or the manager was
+/// dropped.
+///
+/// # Example
+/// ```no_run
+/// # use discord::model::gateway::payload::outgoing::UpdatePresence;
+/// # async fn run(manager: discord::manager::Manager, presence: UpdatePresence) {
+/// let sender = manager.command_sender();
+/// tokio::spawn(async move {
+///     if let Err(e) = sender.update_presence(presence).await {
+///         println!("could not update presence: {}", e);
+///     }
+/// });
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct CommandSender {
+    pub(super) tx: mpsc::Sender<GatewayCommand>,
+}
+
+impl CommandSender {
+    /// Update the presence of the bot
+    pub async fn update_presence(&self, presence: UpdatePresence) -> Result<(), Error> {
+        self.send(GatewayCommand::UpdatePresence(presence)).await
+    }
+
+    /// Join, move or leave a voice channel
+    pub async fn update_voice_state(&self, voice_state: UpdateVoiceState) -> Result<(), Error> {
+        self.send(GatewayCommand::UpdateVoiceState(voice_state))
+            .await
+    }
+
+    /// Request the members of a guild, which are received as `GuildMembersChunk` events
+    pub async fn request_guild_members(&self, request: RequestGuildMembers) -> Result<(), Error> {
+        self.send(GatewayCommand::RequestGulidMembers(request))
+            .await
+    }
+
+    /// Returns true if the connection failed or the manager was dropped
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    async fn send(&self, cmd: GatewayCommand) -> Result<(), Error> {
+        self.tx
+            .send(cmd)
+            .await
+            .map_err(|_| Error::GatewayClosed(None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::id::Id;
+
+    #[tokio::test]
+    async fn closed() {
+        let (tx, mut rx) = mpsc::channel(COMMAND_BUFFER);
+        let sender = CommandSender { tx };
+        let request = || RequestGuildMembers::builder(Id::new(1)).query("", None);
+
+        sender
+            .clone()
+            .request_guild_members(request())
+            .await
+            .unwrap();
+        assert!(matches!(
+            rx.recv().await,
+            Some(GatewayCommand::RequestGulidMembers(_))
+        ));
+
+        rx.close();
+        assert!(sender.is_closed());
+        assert!(matches!(
+            sender.request_guild_members(request()).await,
+            Err(Error::GatewayClosed(None))
+        ));
+    }
+}
@@ -0,0 +1,266 @@
+//! Identify permits shared across processes
+//!
+//! Shards of a bot that run in multiple processes have to share the identify rate limit. One
+//! process runs an identify queue server (e.g. the `identify-queue` binary) and the managers of
+//! all processes request their identify permits from it with a [`RemoteIdentifyQueue`].
+//!
+//! The protocol is line based: the client sends the shard as `<index> <total>\n` and the server
+//! answers with `ok\n` once the shard is allowed to identify or with `error <reason>\n`. The
+//! client must not send anything while it waits, the server drops the request if the client
+//! closes the connection or sends another line before the permit was granted.
+//!
+//! Addresses are either `host:port` for TCP or `unix:<path>` for Unix domain sockets.
+
+use crate::{
+    proto::{IdentifyQueue, ShardId},
+    Error,
+};
+use futures::future::BoxFuture;
+use std::{fmt::Debug, time::Instant};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+/// Source of identify permits for a [`Manager`](super::Manager)
+///
+/// The [`IdentifyQueue`] is used by default, which only coordinates the identifies within one
+/// process.
+pub trait IdentifyClient: Debug + Send + Sync {
+    /// Waits until the shard is allowed to identify
+    fn acquire(&self, shard: ShardId) -> BoxFuture<'static, Result<(), Error>>;
+}
+
+impl IdentifyClient for IdentifyQueue {
+    fn acquire(&self, shard: ShardId) -> BoxFuture<'static, Result<(), Error>> {
+        let queue = self.clone();
+        Box::pin(async move {
+            while let Err(deadline) = queue.try_acquire(shard, Instant::now()) {
+                tokio::time::sleep_until(deadline.into()).await;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Client of an identify queue server
+///
+/// # Example
+/// ```no_run
+/// # use discord::{proto::*, model::gateway::Intents};
+/// # use discord::manager::identify::RemoteIdentifyQueue;
+/// # use std::sync::Arc;
+/// # async fn run() -> Result<(), discord::Error> {
+/// let config = Config::new("<token>", Intents::all()).shard(ShardId::new(3, 16)?);
+/// let mut manager = discord::manager::connect(config).await?;
+/// manager.set_identify_client(Arc::new(RemoteIdentifyQueue::new("127.0.0.1:7878")));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RemoteIdentifyQueue {
+    addr: String,
+}
+
+impl RemoteIdentifyQueue {
+    /// Create a client for the server listening on `addr`
+    pub fn new<S>(addr: S) -> Self
+    where
+        S: Into<String>,
+    {
+        RemoteIdentifyQueue { addr: addr.into() }
+    }
+}
+
+impl IdentifyClient for RemoteIdentifyQueue {
+    fn acquire(&self, shard: ShardId) -> BoxFuture<'static, Result<(), Error>> {
+        let addr = self.addr.clone();
+        Box::pin(async move {
+            match addr.strip_prefix("unix:") {
+                #[cfg(unix)]
+                Some(path) => request(tokio::net::UnixStream::connect(path).await?, shard).await,
+                #[cfg(not(unix))]
+                Some(_) => Err(Error::Custom("unix sockets are not supported".to_string())),
+                None => request(TcpStream::connect(&addr).await?, shard).await,
+            }
+        })
+    }
+}
+
+/// Serves the identify permits of the queue to the clients connecting to `addr`
+///
+/// The socket file of a Unix domain socket is replaced if no server is listening on it anymore.
+pub async fn serve(addr: &str, queue: IdentifyQueue) -> Result<(), Error> {
+    match addr.strip_prefix("unix:") {
+        #[cfg(unix)]
+        Some(path) => {
+            remove_stale_socket(path).await?;
+            let listener = tokio::net::UnixListener::bind(path)?;
+            loop {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(handle(stream, queue.clone()));
+            }
+        }
+        #[cfg(not(unix))]
+        Some(_) => Err(Error::Custom("unix sockets are not supported".to_string())),
+        None => {
+            let listener = TcpListener::bind(addr).await?;
+            loop {
+                let (stream, peer) = listener.accept().await?;
+                log::debug!("identify client connected: {}", peer);
+                tokio::spawn(handle(stream, queue.clone()));
+            }
+        }
+    }
+}
+
+/// Removes the socket file of a server that was not shut down cleanly
+#[cfg(unix)]
+async fn remove_stale_socket(path: &str) -> Result<(), Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let is_socket = std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+    // binding fails as usual if another server is still listening
+    if is_socket && tokio::net::UnixStream::connect(path).await.is_err() {
+        log::info!("removing stale socket {}", path);
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Requests a permit for the shard over the stream
+async fn request<S>(stream: S, shard: ShardId) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+    let msg = format!("{} {}\n", shard.index(), shard.total());
+    stream.write_all(msg.as_bytes()).await?;
+
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    match line.trim_end() {
+        "ok" => Ok(()),
+        "" => Err(Error::Custom(
+            "identify queue closed the connection".to_string(),
+        )),
+        line => Err(Error::Custom(
+            line.strip_prefix("error ").unwrap_or(line).to_string(),
+        )),
+    }
+}
+
+/// Answers the requests of one client
+async fn handle<S>(stream: S, queue: IdentifyQueue)
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let response = match parse_shard(&line) {
+            Ok(shard) => tokio::select! {
+                result = queue.acquire(shard) => match result {
+                    Ok(()) => "ok".to_string(),
+                    Err(e) => format!("error {}", e),
+                },
+                // the permit is not taken for clients that gave up waiting
+                _ = lines.next_line() => {
+                    log::debug!("identify client of shard {} left before its permit", shard);
+                    break;
+                }
+            },
+            Err(e) => format!("error {}", e),
+        };
+
+        if writer
+            .write_all(format!("{}\n", response).as_bytes())
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+fn parse_shard(line: &str) -> Result<ShardId, Error> {
+    let mut parts = line.split_whitespace().map(str::parse::<u64>);
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(index)), Some(Ok(total)), None) => ShardId::new(index, total),
+        _ => Err(Error::Custom(format!(
+            "invalid identify request {:?}",
+            line
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn remote_permits() {
+        let queue = IdentifyQueue::new(1);
+        let (client, server) = tokio::io::duplex(64);
+        tokio::spawn(handle(server, queue.clone()));
+
+        let mut client = BufReader::new(client);
+        request(&mut client, ShardId::new(0, 2).unwrap())
+            .await
+            .unwrap();
+        // the permit is taken from the queue of the server
+        assert!(queue
+            .try_acquire(ShardId::new(1, 2).unwrap(), Instant::now())
+            .is_err());
+
+        client.write_all(b"2 2\n").await.unwrap();
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("error "));
+    }
+
+    #[tokio::test]
+    async fn client_left() {
+        let queue = IdentifyQueue::new(1);
+        let shard = ShardId::new(0, 1).unwrap();
+        queue.try_acquire(shard, Instant::now()).unwrap();
+
+        let (mut client, server) = tokio::io::duplex(64);
+        let server = tokio::spawn(handle(server, queue.clone()));
+
+        // the client gives up while the server waits for the next permit
+        client.write_all(b"0 1\n").await.unwrap();
+        drop(client);
+        tokio::time::timeout(std::time::Duration::from_secs(1), server)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let available_at = queue.available_at(shard, Instant::now());
+        assert!(queue.try_acquire(shard, available_at).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stale_socket() {
+        let path = std::env::temp_dir().join(format!("identify-queue-{}.sock", std::process::id()));
+        let addr = format!("unix:{}", path.display());
+        let _ = std::fs::remove_file(&path);
+
+        // the socket file stays behind after the listener is dropped
+        drop(tokio::net::UnixListener::bind(&path).unwrap());
+        tokio::spawn(async move { serve(&addr, IdentifyQueue::new(1)).await });
+
+        let mut result = Err(Error::GatewayClosed(None));
+        for _ in 0..100 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            if let Ok(stream) = tokio::net::UnixStream::connect(&path).await {
+                result = request(stream, ShardId::ONE).await;
+                break;
+            }
+        }
+        let _ = std::fs::remove_file(&path);
+        result.unwrap();
+    }
+}
@@ -0,0 +1,47 @@
+//! Receiving events as a [`Stream`]
+
+use super::Manager;
+use crate::{model::gateway::event::Event, Error};
+use futures::stream::{BoxStream, Stream, StreamExt};
+use std::{
+    fmt::Debug,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Stream of the events of a [`Manager`], created with [`Manager::into_stream()`]
+///
+/// Errors are yielded as items. The stream ends after the connection failed.
+pub struct EventStream {
+    inner: BoxStream<'static, Result<Event, Error>>,
+}
+
+impl EventStream {
+    pub(super) fn new(manager: Manager) -> Self {
+        let inner = futures::stream::unfold(Some(manager), |manager| async move {
+            let mut manager = manager?;
+            let event = manager.recv().await;
+
+            let failed = event.is_err() && manager.context().failed().is_some();
+            Some((event, if failed { None } else { Some(manager) }))
+        });
+
+        EventStream {
+            inner: inner.boxed(),
+        }
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Result<Event, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+impl Debug for EventStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventStream").finish_non_exhaustive()
+    }
+}
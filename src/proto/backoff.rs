@@ -0,0 +1,142 @@
+//! Backoff between reconnect attempts
+//!
+//! Consecutive reconnects are delayed exponentially, so that a client does not hammer the gateway
+//! while it is unavailable. The counter is reset once a connection stayed up long enough to be
+//! considered stable.
+
+use super::Rng;
+use std::time::Duration;
+
+/// Policy for delaying reconnect attempts
+///
+/// The first reconnect after a stable connection happens immediately. Every further attempt is
+/// delayed by `initial_delay * multiplier^(attempt - 2)`, capped at `max_delay`.
+///
+/// # Example
+/// ```
+/// # use discord::proto::BackoffPolicy;
+/// # use std::time::Duration;
+/// let policy = BackoffPolicy::default();
+/// assert_eq!(Duration::ZERO, policy.base_delay(1));
+/// assert_eq!(Duration::from_secs(1), policy.base_delay(2));
+/// assert_eq!(Duration::from_secs(2), policy.base_delay(3));
+/// assert_eq!(Duration::from_secs(60), policy.base_delay(20));
+/// ```
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BackoffPolicy {
+    /// delay of the first delayed attempt
+    pub initial_delay: Duration,
+    /// upper bound of the delay
+    pub max_delay: Duration,
+    /// factor by which the delay grows with every attempt
+    pub multiplier: u32,
+    /// randomize the delay between half and the full delay
+    pub jitter: bool,
+    /// number of consecutive reconnect attempts after which the client gives up
+    ///
+    /// Connections that are closed before they are stable count as failed attempts as well. The
+    /// client never gives up if this is `None`.
+    pub max_attempts: Option<u32>,
+    /// time a connection has to stay up until the attempt counter is reset
+    pub stable_after: Duration,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        BackoffPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2,
+            jitter: true,
+            max_attempts: None,
+            stable_after: Duration::from_secs(60),
+        }
+    }
+}
+
+impl BackoffPolicy {
+    /// Returns the delay of the `attempt`th consecutive attempt without jitter
+    pub fn base_delay(&self, attempt: u32) -> Duration {
+        if attempt <= 1 {
+            return Duration::ZERO;
+        }
+
+        let factor = self.multiplier.saturating_pow(attempt - 2);
+        self.initial_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+
+    /// Returns the delay of the `attempt`th consecutive attempt with jitter
+    pub(crate) fn delay(&self, attempt: u32, rng: &mut Rng) -> Duration {
+        let delay = self.base_delay(attempt);
+        if self.jitter {
+            delay / 2 + (delay / 2).mul_f64(rng.next_f64())
+        } else {
+            delay
+        }
+    }
+
+    /// Returns true if the client should give up after `attempts` failed attempts
+    pub fn exhausted(&self, attempts: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempts >= max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_delay() {
+        let policy = BackoffPolicy {
+            jitter: false,
+            ..Default::default()
+        };
+        let mut rng = Rng::new(0);
+
+        let delays: Vec<_> = (1..=9).map(|n| policy.delay(n, &mut rng)).collect();
+        let secs = |s| Duration::from_secs(s);
+        assert_eq!(
+            vec![
+                secs(0),
+                secs(1),
+                secs(2),
+                secs(4),
+                secs(8),
+                secs(16),
+                secs(32),
+                secs(60),
+                secs(60)
+            ],
+            delays
+        );
+        assert_eq!(secs(60), policy.base_delay(u32::MAX));
+    }
+
+    #[test]
+    fn jitter() {
+        let policy = BackoffPolicy::default();
+        let mut rng = Rng::new(42);
+
+        for attempt in 1..20 {
+            let base = policy.base_delay(attempt);
+            let delay = policy.delay(attempt, &mut rng);
+            assert!(delay >= base / 2);
+            assert!(delay <= base);
+        }
+    }
+
+    #[test]
+    fn max_attempts() {
+        let policy = BackoffPolicy::default();
+        assert!(!policy.exhausted(u32::MAX));
+
+        let policy = BackoffPolicy {
+            max_attempts: Some(3),
+            ..Default::default()
+        };
+        assert!(!policy.exhausted(2));
+        assert!(policy.exhausted(3));
+    }
+}
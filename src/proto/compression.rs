@@ -0,0 +1,472 @@
+//! Transport compression
+//!
+//! With transport compression enabled all messages from the gateway are part of a single
+//! compressed stream that is shared for the whole lifetime of the websocket connection.
+//! With `zlib-stream` a message can be split over multiple websocket frames, while `zstd-stream`
+//! sends every message in one websocket message.
+//!
+//! <https://discord.com/developers/docs/topics/gateway#transport-compression>
+
+use super::Compression;
+use crate::Error;
+#[cfg(feature = "zlib")]
+use flate2::{Decompress, FlushDecompress, Status};
+#[cfg(feature = "zstd")]
+use zstd::stream::raw::{Decoder, InBuffer, Operation, OutBuffer};
+
+/// Decompressor for the transport compression of a connection
+#[derive(Debug)]
+pub enum Decompressor {
+    /// `zlib-stream` decompressor
+    #[cfg(feature = "zlib")]
+    Zlib(Inflater),
+    /// `zstd-stream` decompressor
+    #[cfg(feature = "zstd")]
+    Zstd(ZstdDecoder),
+}
+
+impl Decompressor {
+    /// Create a new decompressor for the given compression
+    pub fn new(compression: Compression) -> Self {
+        match compression {
+            #[cfg(feature = "zlib")]
+            Compression::ZlibStream => Decompressor::Zlib(Inflater::new()),
+            #[cfg(feature = "zstd")]
+            Compression::ZstdStream => Decompressor::Zstd(ZstdDecoder::new()),
+        }
+    }
+
+    /// Reset the decompressor so it can be used for a new connection
+    pub fn reset(&mut self) {
+        match *self {
+            #[cfg(feature = "zlib")]
+            Decompressor::Zlib(ref mut inflater) => inflater.reset(),
+            #[cfg(feature = "zstd")]
+            Decompressor::Zstd(ref mut decoder) => decoder.reset(),
+        }
+    }
+
+    /// Feed a binary websocket frame to the decompressor.
+    ///
+    /// Returns the decompressed message if the frame completed it or `None` if more frames are
+    /// needed. The zstd decoder expects whole websocket messages, see `ZstdDecoder::decode()`.
+    #[cfg_attr(not(any(feature = "zlib", feature = "zstd")), allow(unused_variables))]
+    pub fn decompress(&mut self, frame: &[u8]) -> Result<Option<&[u8]>, Error> {
+        match *self {
+            #[cfg(feature = "zlib")]
+            Decompressor::Zlib(ref mut inflater) => inflater.inflate(frame),
+            #[cfg(feature = "zstd")]
+            Decompressor::Zstd(ref mut decoder) => decoder.decode(frame).map_err(Error::ZstdError),
+        }
+    }
+}
+
+/// Every complete `zlib-stream` message ends with a `Z_SYNC_FLUSH` suffix
+#[cfg(feature = "zlib")]
+const ZLIB_SUFFIX: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Initial size of the output buffer
+#[cfg(any(feature = "zlib", feature = "zstd"))]
+const INITIAL_BUFFER_SIZE: usize = 32 * 1024;
+
+/// Decompressor for the `zlib-stream` transport compression
+///
+/// Buffers frames until a complete message has been received and inflates it.
+/// One inflater has to be used per websocket connection.
+#[cfg(feature = "zlib")]
+pub struct Inflater {
+    decompress: Decompress,
+    /// compressed input of an incomplete message
+    buffer: Vec<u8>,
+    /// decompressed output of the last message
+    output: Vec<u8>,
+}
+
+#[cfg(feature = "zlib")]
+impl std::fmt::Debug for Inflater {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Inflater")
+            .field("total_in", &self.decompress.total_in())
+            .field("total_out", &self.decompress.total_out())
+            .field("buffered", &self.buffer.len())
+            .finish()
+    }
+}
+
+#[cfg(feature = "zlib")]
+impl Default for Inflater {
+    fn default() -> Self {
+        Inflater::new()
+    }
+}
+
+#[cfg(feature = "zlib")]
+impl Inflater {
+    /// Create a new inflater for a fresh connection
+    pub fn new() -> Self {
+        Inflater {
+            decompress: Decompress::new(true),
+            buffer: Vec::new(),
+            output: Vec::with_capacity(INITIAL_BUFFER_SIZE),
+        }
+    }
+
+    /// Reset the inflater so it can be used for a new connection
+    pub fn reset(&mut self) {
+        self.decompress.reset(true);
+        self.buffer.clear();
+        self.output.clear();
+    }
+
+    /// Feed a binary websocket frame to the inflater.
+    ///
+    /// Returns the decompressed message if the frame completed it or `None` if more frames are
+    /// needed. Fails with [`Error::InvalidZlibStream`] if the message does not continue the
+    /// stream, e.g. because it carries data after the end of the stream.
+    pub fn inflate(&mut self, frame: &[u8]) -> Result<Option<&[u8]>, Error> {
+        self.buffer.extend_from_slice(frame);
+
+        if !self.buffer.ends_with(&ZLIB_SUFFIX) {
+            log::trace!("buffering incomplete message: {} bytes", self.buffer.len());
+            return Ok(None);
+        }
+
+        self.output.clear();
+        let mut offset = 0;
+
+        loop {
+            if self.output.len() == self.output.capacity() {
+                self.output
+                    .reserve(INITIAL_BUFFER_SIZE.max(self.buffer.len()));
+            }
+
+            let total_in = self.decompress.total_in();
+            let total_out = self.decompress.total_out();
+            let status = self.decompress.decompress_vec(
+                &self.buffer[offset..],
+                &mut self.output,
+                FlushDecompress::Sync,
+            )?;
+            offset += (self.decompress.total_in() - total_in) as usize;
+
+            let progress =
+                self.decompress.total_in() != total_in || self.decompress.total_out() != total_out;
+            let consumed = offset == self.buffer.len();
+            if !consumed && (status == Status::StreamEnd || !progress) {
+                log::warn!(
+                    "invalid zlib stream: status= {:?} consumed= {} buffered= {}",
+                    status,
+                    offset,
+                    self.buffer.len()
+                );
+                self.buffer.clear();
+                return Err(Error::InvalidZlibStream);
+            }
+
+            // all input has been consumed and no output is pending
+            if consumed
+                && (!progress
+                    || status == Status::StreamEnd
+                    || self.output.len() < self.output.capacity())
+            {
+                break;
+            }
+        }
+
+        self.buffer.clear();
+        Ok(Some(&self.output))
+    }
+}
+
+/// Decompressor for the `zstd-stream` transport compression
+///
+/// All messages of a connection are part of a single zstd stream, so the decoder keeps its
+/// context between messages. One decoder has to be used per websocket connection.
+///
+/// The gateway flushes the stream after every message, so each websocket message decompresses
+/// to exactly one gateway message.
+#[cfg(feature = "zstd")]
+pub struct ZstdDecoder {
+    decoder: Decoder<'static>,
+    /// decompressed output of the last message
+    output: Vec<u8>,
+}
+
+#[cfg(feature = "zstd")]
+impl std::fmt::Debug for ZstdDecoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ZstdDecoder")
+            .field("output", &self.output.len())
+            .finish()
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Default for ZstdDecoder {
+    fn default() -> Self {
+        ZstdDecoder::new()
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl ZstdDecoder {
+    /// Create a new decoder for a fresh connection
+    pub fn new() -> Self {
+        ZstdDecoder {
+            decoder: Decoder::new().expect("could not create zstd decompression context"),
+            output: Vec::with_capacity(INITIAL_BUFFER_SIZE),
+        }
+    }
+
+    /// Reset the decoder so it can be used for a new connection
+    pub fn reset(&mut self) {
+        if let Err(err) = self.decoder.reinit() {
+            log::warn!("could not reset zstd context: {}", err);
+            self.decoder = Decoder::new().expect("could not create zstd decompression context");
+        }
+        self.output.clear();
+    }
+
+    /// Feed a binary websocket message to the decoder.
+    ///
+    /// Each call has to receive one whole websocket message and returns its decompressed output,
+    /// or `None` if the message did not decompress to any output.
+    pub fn decode(&mut self, frame: &[u8]) -> std::io::Result<Option<&[u8]>> {
+        self.output.clear();
+        let mut input = InBuffer::around(frame);
+
+        loop {
+            if self.output.len() == self.output.capacity() {
+                self.output.reserve(INITIAL_BUFFER_SIZE.max(frame.len()));
+            }
+
+            let pos = self.output.len();
+            let mut output = OutBuffer::around_pos(&mut self.output, pos);
+            self.decoder.run(&mut input, &mut output)?;
+
+            // all input has been consumed and the output did not run out of space
+            if input.pos() == frame.len() && self.output.len() < self.output.capacity() {
+                break;
+            }
+        }
+
+        if self.output.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(&self.output))
+    }
+}
+
+#[cfg(all(test, feature = "zlib"))]
+mod tests {
+    use super::*;
+    use flate2::{Compress, Compression, FlushCompress};
+
+    /// Compresses messages the same way the gateway does
+    struct Deflater(Compress);
+
+    impl Deflater {
+        fn new() -> Self {
+            Deflater(Compress::new(Compression::default(), true))
+        }
+
+        fn deflate(&mut self, msg: &[u8]) -> Vec<u8> {
+            let mut output = Vec::with_capacity(msg.len() + 64);
+            self.0
+                .compress_vec(msg, &mut output, FlushCompress::Sync)
+                .unwrap();
+            assert!(output.ends_with(&ZLIB_SUFFIX));
+            output
+        }
+    }
+
+    #[test]
+    fn stream() {
+        let mut deflater = Deflater::new();
+        let mut inflater = Inflater::new();
+
+        let messages = [
+            r#"{"op":10,"d":{"heartbeat_interval":41250}}"#,
+            r#"{"op":11,"d":null}"#,
+            r#"{"op":11,"d":null}"#,
+        ];
+
+        for msg in messages {
+            let frame = deflater.deflate(msg.as_bytes());
+            assert_eq!(Some(msg.as_bytes()), inflater.inflate(&frame).unwrap());
+        }
+    }
+
+    #[test]
+    fn partial_frames() {
+        let mut deflater = Deflater::new();
+        let mut inflater = Inflater::new();
+
+        let msg = r#"{"op":10,"d":{"heartbeat_interval":41250}}"#;
+        let frame = deflater.deflate(msg.as_bytes());
+        let (first, second) = frame.split_at(frame.len() - 2);
+
+        assert_eq!(None, inflater.inflate(first).unwrap());
+        assert_eq!(Some(msg.as_bytes()), inflater.inflate(second).unwrap());
+    }
+
+    #[test]
+    fn large_message() {
+        let mut deflater = Deflater::new();
+        let mut inflater = Inflater::new();
+
+        let msg: String = (0..200_000)
+            .map(|i| ((i % 26) as u8 + b'a') as char)
+            .collect();
+        let frame = deflater.deflate(msg.as_bytes());
+
+        assert_eq!(Some(msg.as_bytes()), inflater.inflate(&frame).unwrap());
+    }
+
+    #[test]
+    fn data_after_stream_end() {
+        let mut inflater = Inflater::new();
+        let msg = br#"{"op":11,"d":null}"#;
+
+        let mut compress = Compress::new(Compression::default(), true);
+        let mut frame = Vec::with_capacity(msg.len() + 64);
+        compress
+            .compress_vec(msg, &mut frame, FlushCompress::Finish)
+            .unwrap();
+        frame.extend_from_slice(&ZLIB_SUFFIX);
+
+        assert!(matches!(
+            inflater.inflate(&frame),
+            Err(Error::InvalidZlibStream)
+        ));
+    }
+
+    #[test]
+    fn reset() {
+        let mut inflater = Inflater::new();
+        let msg = br#"{"op":11,"d":null}"#;
+
+        let frame = Deflater::new().deflate(msg);
+        assert_eq!(Some(&msg[..]), inflater.inflate(&frame).unwrap());
+
+        // a new connection starts a new stream
+        inflater.reset();
+        let frame = Deflater::new().deflate(msg);
+        assert_eq!(Some(&msg[..]), inflater.inflate(&frame).unwrap());
+    }
+}
+
+#[cfg(all(test, feature = "zstd"))]
+mod zstd_tests {
+    use super::*;
+    use zstd::stream::raw::Encoder;
+
+    /// Compresses messages the same way the gateway does: a single stream that is flushed after
+    /// every message
+    struct ZstdEncoder(Encoder<'static>);
+
+    impl ZstdEncoder {
+        fn new() -> Self {
+            ZstdEncoder(Encoder::new(3).unwrap())
+        }
+
+        fn encode(&mut self, msg: &[u8]) -> Vec<u8> {
+            let mut output = Vec::with_capacity(msg.len() + 64);
+            let mut input = InBuffer::around(msg);
+
+            while input.pos() < msg.len() {
+                let pos = output.len();
+                output.reserve(msg.len());
+                self.0
+                    .run(&mut input, &mut OutBuffer::around_pos(&mut output, pos))
+                    .unwrap();
+            }
+
+            loop {
+                let pos = output.len();
+                output.reserve(64);
+                let remaining = self
+                    .0
+                    .flush(&mut OutBuffer::around_pos(&mut output, pos))
+                    .unwrap();
+                if remaining == 0 {
+                    break;
+                }
+            }
+
+            output
+        }
+    }
+
+    #[test]
+    fn stream() {
+        let mut encoder = ZstdEncoder::new();
+        let mut decoder = ZstdDecoder::new();
+
+        let messages = [
+            r#"{"op":10,"d":{"heartbeat_interval":41250}}"#,
+            r#"{"op":11,"d":null}"#,
+            r#"{"op":11,"d":null}"#,
+        ];
+
+        for msg in messages {
+            let frame = encoder.encode(msg.as_bytes());
+            assert_eq!(Some(msg.as_bytes()), decoder.decode(&frame).unwrap());
+        }
+    }
+
+    #[test]
+    fn large_message() {
+        let mut encoder = ZstdEncoder::new();
+        let mut decoder = ZstdDecoder::new();
+
+        let msg: Vec<u8> = (0..500_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let frame = encoder.encode(&msg);
+
+        assert_eq!(Some(&msg[..]), decoder.decode(&frame).unwrap());
+    }
+
+    #[test]
+    fn reset() {
+        let mut decoder = ZstdDecoder::new();
+        let msg = br#"{"op":11,"d":null}"#;
+
+        let frame = ZstdEncoder::new().encode(msg);
+        assert_eq!(Some(&msg[..]), decoder.decode(&frame).unwrap());
+
+        // a new connection starts a new stream
+        decoder.reset();
+        let frame = ZstdEncoder::new().encode(msg);
+        assert_eq!(Some(&msg[..]), decoder.decode(&frame).unwrap());
+    }
+
+    #[test]
+    fn multiple_blocks() {
+        let mut encoder = ZstdEncoder::new();
+        let mut decoder = ZstdDecoder::new();
+
+        // incompressible data spans multiple blocks
+        let mut x = 1u32;
+        let msg: Vec<u8> = (0..500_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        let frame = encoder.encode(&msg);
+        assert_eq!(Some(&msg[..]), decoder.decode(&frame).unwrap());
+
+        // the next message does not contain output of the previous one
+        let msg = br#"{"op":11,"d":null}"#;
+        let frame = encoder.encode(msg);
+        assert_eq!(Some(&msg[..]), decoder.decode(&frame).unwrap());
+    }
+
+    #[test]
+    fn invalid_data() {
+        let mut decoder = ZstdDecoder::new();
+        assert!(decoder.decode(b"definitely not zstd").is_err());
+    }
+}
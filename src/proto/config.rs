@@ -3,7 +3,55 @@ use twilight_model::gateway::{
     Intents,
 };
 
-use crate::LIB_NAME;
+use super::{BackoffPolicy, ShardId};
+use crate::{API_VERSION, LIB_NAME};
+
+/// Encoding of the gateway messages
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Encoding {
+    /// JSON encoding
+    #[default]
+    Json,
+    /// Erlang term format encoding (requires the `etf` feature)
+    #[cfg(feature = "etf")]
+    Etf,
+}
+
+impl Encoding {
+    /// Returns the value of the `encoding` query parameter
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Encoding::Json => "json",
+            #[cfg(feature = "etf")]
+            Encoding::Etf => "etf",
+        }
+    }
+}
+
+/// Transport compression of the gateway connection
+///
+/// <https://discord.com/developers/docs/topics/gateway#transport-compression>
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Compression {
+    /// `zlib-stream` compression
+    #[cfg(feature = "zlib")]
+    ZlibStream,
+    /// `zstd-stream` compression
+    #[cfg(feature = "zstd")]
+    ZstdStream,
+}
+
+impl Compression {
+    /// Returns the value of the `compress` query parameter
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "zlib")]
+            Compression::ZlibStream => "zlib-stream",
+            #[cfg(feature = "zstd")]
+            Compression::ZstdStream => "zstd-stream",
+        }
+    }
+}
 
 /// Connection Config
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -14,8 +62,15 @@ pub struct Config {
     pub intents: Intents,
     pub large_threshold: u64,
     pub presence: Option<UpdatePresencePayload>,
-    pub shard: [u64; 2],
+    pub shard: ShardId,
     pub token: String,
+    /// Seed for the random number generator used for jitter.
+    ///
+    /// A random seed is used if none is set.
+    pub rng_seed: Option<u64>,
+    pub compression: Option<Compression>,
+    pub encoding: Encoding,
+    pub backoff: BackoffPolicy,
 }
 
 impl From<(&str, Intents)> for Config {
@@ -36,8 +91,12 @@ impl Config {
             intents,
             large_threshold: 50,
             presence: None,
-            shard: [0, 1],
+            shard: ShardId::ONE,
             token: token.into(),
+            rng_seed: None,
+            compression: None,
+            encoding: Encoding::default(),
+            backoff: BackoffPolicy::default(),
         }
     }
 
@@ -57,14 +116,61 @@ impl Config {
         self.large_threshold = large_threshold;
         self
     }
-    /// set the gateway url
-    pub fn shard(mut self, shard: [u64; 2]) -> Self {
+    /// set the shard of the connection
+    pub fn shard(mut self, shard: ShardId) -> Self {
         self.shard = shard;
         self
     }
-    /// set the gateway url
+    /// set the gateway url, the manager uses it instead of requesting `/gateway/bot`
     pub fn gateway_url(mut self, url: String) -> Self {
         self.gateway_url = Some(url);
         self
     }
+    /// set the transport compression
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+    /// set the encoding of the gateway messages
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+    /// set the backoff policy for reconnect attempts
+    pub fn backoff(mut self, backoff: BackoffPolicy) -> Self {
+        self.backoff = backoff;
+        self
+    }
+    /// set a fixed seed for jitter calculations (useful for deterministic tests)
+    pub fn rng_seed(mut self, seed: u64) -> Self {
+        self.rng_seed = Some(seed);
+        self
+    }
+
+    /// Append the query parameters of this config to a gateway url
+    ///
+    /// # Example
+    /// ```
+    /// # use discord::{proto::Config, model::gateway::Intents};
+    /// let config = Config::new("TOKEN", Intents::empty());
+    /// assert_eq!(
+    ///     "wss://gateway.discord.gg/?v=10&encoding=json",
+    ///     config.connection_url("wss://gateway.discord.gg")
+    /// );
+    /// ```
+    pub fn connection_url(&self, base_url: &str) -> String {
+        let mut url = format!(
+            "{}/?v={}&encoding={}",
+            base_url.trim_end_matches('/'),
+            API_VERSION,
+            self.encoding.as_str()
+        );
+
+        if let Some(compression) = self.compression {
+            url.push_str("&compress=");
+            url.push_str(compression.as_str());
+        }
+
+        url
+    }
 }
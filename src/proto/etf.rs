@@ -0,0 +1,509 @@
+//! Erlang External Term Format
+//!
+//! Encoder and decoder for the subset of ETF used by the discord gateway.
+//! Terms are converted from and into JSON values, so that the same (de)serialization of the
+//! models can be used for both encodings.
+//!
+//! <https://discord.com/developers/docs/topics/gateway#etfjson>
+//! <https://www.erlang.org/doc/apps/erts/erl_ext_dist.html>
+
+use serde_json::{Map, Number, Value};
+use std::fmt::Display;
+use twilight_model::gateway::event::{GatewayEvent, GatewayEventDeserializerOwned};
+
+const VERSION: u8 = 131;
+
+const NEW_FLOAT_EXT: u8 = 70;
+const SMALL_INTEGER_EXT: u8 = 97;
+const INTEGER_EXT: u8 = 98;
+const FLOAT_EXT: u8 = 99;
+const ATOM_EXT: u8 = 100;
+const SMALL_TUPLE_EXT: u8 = 104;
+const LARGE_TUPLE_EXT: u8 = 105;
+const NIL_EXT: u8 = 106;
+const STRING_EXT: u8 = 107;
+const LIST_EXT: u8 = 108;
+const BINARY_EXT: u8 = 109;
+const SMALL_BIG_EXT: u8 = 110;
+const LARGE_BIG_EXT: u8 = 111;
+const SMALL_ATOM_EXT: u8 = 115;
+const MAP_EXT: u8 = 116;
+const ATOM_UTF8_EXT: u8 = 118;
+const SMALL_ATOM_UTF8_EXT: u8 = 119;
+
+/// Errors that can occur while decoding ETF
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum EtfError {
+    /// The input does not start with the ETF version byte
+    InvalidVersion(u8),
+    /// The input ended before the term was complete
+    UnexpectedEof,
+    /// The term tag is not supported
+    UnsupportedTag(u8),
+    /// An integer does not fit into 64 bits
+    IntegerTooLarge,
+    /// A float is not a finite number
+    InvalidFloat,
+    /// A string is not valid UTF-8
+    InvalidUtf8,
+    /// A map key cannot be converted to a string
+    InvalidKey,
+    /// There is data left after the term
+    TrailingData,
+}
+
+impl Display for EtfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EtfError::InvalidVersion(v) => write!(f, "invalid etf version: {}", v),
+            EtfError::UnexpectedEof => f.write_str("unexpected end of input"),
+            EtfError::UnsupportedTag(tag) => write!(f, "unsupported etf tag: {}", tag),
+            EtfError::IntegerTooLarge => f.write_str("integer does not fit into 64 bits"),
+            EtfError::InvalidFloat => f.write_str("float is not a finite number"),
+            EtfError::InvalidUtf8 => f.write_str("string is not valid utf-8"),
+            EtfError::InvalidKey => f.write_str("map key is not a string"),
+            EtfError::TrailingData => f.write_str("trailing data after term"),
+        }
+    }
+}
+
+impl std::error::Error for EtfError {}
+
+/// Decodes an ETF term into a JSON value
+///
+/// The atoms `nil`, `true` and `false` are decoded as `null` and booleans, all other atoms,
+/// binaries and strings are decoded as strings. Tuples and lists are decoded as arrays.
+pub fn decode(input: &[u8]) -> Result<Value, EtfError> {
+    let mut decoder = Decoder { input, pos: 0 };
+
+    let version = decoder.u8()?;
+    if version != VERSION {
+        return Err(EtfError::InvalidVersion(version));
+    }
+
+    let value = decoder.term()?;
+    if decoder.pos != input.len() {
+        return Err(EtfError::TrailingData);
+    }
+
+    Ok(value)
+}
+
+/// Encodes a JSON value as an ETF term
+///
+/// `null` and booleans are encoded as atoms, strings as binaries and objects as maps with binary
+/// keys.
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut output = vec![VERSION];
+    encode_term(value, &mut output);
+    output
+}
+
+/// Deserializes an ETF encoded gateway event
+pub(crate) fn deserialize_event(input: &[u8]) -> Result<GatewayEvent, crate::Error> {
+    use serde::de::{DeserializeSeed, Error};
+
+    let value = decode(input)?;
+
+    let op = value
+        .get("op")
+        .and_then(Value::as_u64)
+        .ok_or_else(|| serde_json::Error::custom("missing opcode"))?;
+    let seq = value.get("s").and_then(Value::as_u64);
+    let event_type = value.get("t").and_then(Value::as_str).map(String::from);
+
+    let deserializer = GatewayEventDeserializerOwned::new(op as u8, seq, event_type);
+    Ok(deserializer.deserialize(value)?)
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], EtfError> {
+        let end = self.pos.checked_add(len).ok_or(EtfError::UnexpectedEof)?;
+        let bytes = self
+            .input
+            .get(self.pos..end)
+            .ok_or(EtfError::UnexpectedEof)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, EtfError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, EtfError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, EtfError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn string(&mut self, len: usize) -> Result<String, EtfError> {
+        let bytes = self.bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| EtfError::InvalidUtf8)
+    }
+
+    /// Latin-1 encoded atoms
+    fn latin1(&mut self, len: usize) -> Result<String, EtfError> {
+        Ok(self.bytes(len)?.iter().map(|b| *b as char).collect())
+    }
+
+    fn term(&mut self) -> Result<Value, EtfError> {
+        match self.u8()? {
+            SMALL_INTEGER_EXT => Ok(Value::from(self.u8()?)),
+            INTEGER_EXT => Ok(Value::from(self.u32()? as i32)),
+            NEW_FLOAT_EXT => {
+                let bytes = self.bytes(8)?;
+                let mut buf = [0; 8];
+                buf.copy_from_slice(bytes);
+                float(f64::from_be_bytes(buf))
+            }
+            FLOAT_EXT => {
+                // 31 bytes of a zero padded float string
+                let bytes = self.bytes(31)?;
+                let s = std::str::from_utf8(bytes).map_err(|_| EtfError::InvalidFloat)?;
+                let n = s
+                    .trim_end_matches('\0')
+                    .parse()
+                    .map_err(|_| EtfError::InvalidFloat)?;
+                float(n)
+            }
+            ATOM_EXT => {
+                let len = self.u16()? as usize;
+                Ok(atom(self.latin1(len)?))
+            }
+            SMALL_ATOM_EXT => {
+                let len = self.u8()? as usize;
+                Ok(atom(self.latin1(len)?))
+            }
+            ATOM_UTF8_EXT => {
+                let len = self.u16()? as usize;
+                Ok(atom(self.string(len)?))
+            }
+            SMALL_ATOM_UTF8_EXT => {
+                let len = self.u8()? as usize;
+                Ok(atom(self.string(len)?))
+            }
+            SMALL_TUPLE_EXT => {
+                let arity = self.u8()? as usize;
+                self.elements(arity)
+            }
+            LARGE_TUPLE_EXT => {
+                let arity = self.u32()? as usize;
+                self.elements(arity)
+            }
+            NIL_EXT => Ok(Value::Array(Vec::new())),
+            STRING_EXT => {
+                let len = self.u16()? as usize;
+                Ok(Value::String(self.string(len)?))
+            }
+            LIST_EXT => {
+                let len = self.u32()? as usize;
+                let list = self.elements(len)?;
+                // proper lists end with an empty list as tail
+                match self.term()? {
+                    Value::Array(tail) if tail.is_empty() => Ok(list),
+                    _ => Err(EtfError::UnsupportedTag(LIST_EXT)),
+                }
+            }
+            BINARY_EXT => {
+                let len = self.u32()? as usize;
+                Ok(Value::String(self.string(len)?))
+            }
+            SMALL_BIG_EXT => {
+                let len = self.u8()? as usize;
+                self.big(len)
+            }
+            LARGE_BIG_EXT => {
+                let len = self.u32()? as usize;
+                self.big(len)
+            }
+            MAP_EXT => {
+                let arity = self.u32()? as usize;
+                let mut map = Map::new();
+                for _ in 0..arity {
+                    let key = match self.term()? {
+                        Value::String(key) => key,
+                        Value::Number(n) => n.to_string(),
+                        Value::Bool(b) => b.to_string(),
+                        Value::Null => "nil".to_string(),
+                        _ => return Err(EtfError::InvalidKey),
+                    };
+                    let value = self.term()?;
+                    map.insert(key, value);
+                }
+                Ok(Value::Object(map))
+            }
+            tag => Err(EtfError::UnsupportedTag(tag)),
+        }
+    }
+
+    fn elements(&mut self, len: usize) -> Result<Value, EtfError> {
+        // do not trust the length for preallocation
+        let mut elements = Vec::with_capacity(len.min(self.input.len() - self.pos));
+        for _ in 0..len {
+            elements.push(self.term()?);
+        }
+        Ok(Value::Array(elements))
+    }
+
+    /// Little endian arbitrary size integer
+    fn big(&mut self, len: usize) -> Result<Value, EtfError> {
+        let negative = self.u8()? != 0;
+        let digits = self.bytes(len)?;
+
+        let mut n: u64 = 0;
+        for (i, digit) in digits.iter().enumerate() {
+            if *digit == 0 {
+                continue;
+            }
+            if i >= 8 {
+                return Err(EtfError::IntegerTooLarge);
+            }
+            n |= (*digit as u64) << (8 * i);
+        }
+
+        if !negative {
+            return Ok(Value::from(n));
+        }
+
+        match n {
+            n if n <= i64::MAX as u64 => Ok(Value::from(-(n as i64))),
+            n if n == i64::MAX as u64 + 1 => Ok(Value::from(i64::MIN)),
+            _ => Err(EtfError::IntegerTooLarge),
+        }
+    }
+}
+
+fn atom(name: String) -> Value {
+    match name.as_str() {
+        "nil" | "null" => Value::Null,
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => Value::String(name),
+    }
+}
+
+fn float(n: f64) -> Result<Value, EtfError> {
+    Number::from_f64(n)
+        .map(Value::Number)
+        .ok_or(EtfError::InvalidFloat)
+}
+
+fn encode_atom(name: &str, output: &mut Vec<u8>) {
+    output.push(SMALL_ATOM_UTF8_EXT);
+    output.push(name.len() as u8);
+    output.extend_from_slice(name.as_bytes());
+}
+
+fn encode_binary(s: &str, output: &mut Vec<u8>) {
+    output.push(BINARY_EXT);
+    output.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    output.extend_from_slice(s.as_bytes());
+}
+
+fn encode_big(n: u64, negative: bool, output: &mut Vec<u8>) {
+    let digits = n.to_le_bytes();
+    let len = 8 - n.leading_zeros() as usize / 8;
+
+    output.push(SMALL_BIG_EXT);
+    output.push(len as u8);
+    output.push(negative as u8);
+    output.extend_from_slice(&digits[..len]);
+}
+
+fn encode_number(n: &Number, output: &mut Vec<u8>) {
+    if let Some(n) = n.as_u64() {
+        if n <= u8::MAX as u64 {
+            output.push(SMALL_INTEGER_EXT);
+            output.push(n as u8);
+        } else if n <= i32::MAX as u64 {
+            output.push(INTEGER_EXT);
+            output.extend_from_slice(&(n as i32).to_be_bytes());
+        } else {
+            encode_big(n, false, output);
+        }
+    } else if let Some(n) = n.as_i64() {
+        if n >= i32::MIN as i64 {
+            output.push(INTEGER_EXT);
+            output.extend_from_slice(&(n as i32).to_be_bytes());
+        } else {
+            encode_big(n.unsigned_abs(), true, output);
+        }
+    } else if let Some(n) = n.as_f64() {
+        output.push(NEW_FLOAT_EXT);
+        output.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+fn encode_term(value: &Value, output: &mut Vec<u8>) {
+    match value {
+        Value::Null => encode_atom("nil", output),
+        Value::Bool(b) => encode_atom(if *b { "true" } else { "false" }, output),
+        Value::Number(n) => encode_number(n, output),
+        Value::String(s) => encode_binary(s, output),
+        Value::Array(list) if list.is_empty() => output.push(NIL_EXT),
+        Value::Array(list) => {
+            output.push(LIST_EXT);
+            output.extend_from_slice(&(list.len() as u32).to_be_bytes());
+            for element in list {
+                encode_term(element, output);
+            }
+            output.push(NIL_EXT);
+        }
+        Value::Object(map) => {
+            output.push(MAP_EXT);
+            output.extend_from_slice(&(map.len() as u32).to_be_bytes());
+            for (key, value) in map {
+                encode_binary(key, output);
+                encode_term(value, output);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// `#{op => 10, d => #{heartbeat_interval => 41250}, s => nil, t => nil}` as encoded by the
+    /// gateway
+    const HELLO: &[u8] = &[
+        131, 116, 0, 0, 0, 4, 100, 0, 1, b'd', 116, 0, 0, 0, 1, 100, 0, 18, b'h', b'e', b'a', b'r',
+        b't', b'b', b'e', b'a', b't', b'_', b'i', b'n', b't', b'e', b'r', b'v', b'a', b'l', 98, 0,
+        0, 161, 34, 100, 0, 2, b'o', b'p', 97, 10, 100, 0, 1, b's', 100, 0, 3, b'n', b'i', b'l',
+        100, 0, 1, b't', 100, 0, 3, b'n', b'i', b'l',
+    ];
+
+    #[test]
+    fn decode_hello() {
+        let value = decode(HELLO).unwrap();
+        assert_eq!(
+            json!({"op": 10, "d": {"heartbeat_interval": 41250}, "s": null, "t": null}),
+            value
+        );
+
+        let event = deserialize_event(HELLO).unwrap();
+        assert!(matches!(event, GatewayEvent::Hello(41250)));
+    }
+
+    #[test]
+    fn encode_heartbeat() {
+        let value = json!({"op": 1, "d": 251});
+        assert_eq!(
+            vec![
+                131, 116, 0, 0, 0, 2, 109, 0, 0, 0, 1, b'd', 97, 251, 109, 0, 0, 0, 2, b'o', b'p',
+                97, 1
+            ],
+            encode(&value)
+        );
+    }
+
+    #[test]
+    fn encode_atoms() {
+        assert_eq!(vec![131, 119, 3, b'n', b'i', b'l'], encode(&Value::Null));
+        assert_eq!(
+            vec![131, 119, 4, b't', b'r', b'u', b'e'],
+            encode(&Value::Bool(true))
+        );
+        assert_eq!(vec![131, 106], encode(&json!([])));
+    }
+
+    #[test]
+    fn big_integers() {
+        // snowflakes do not fit into 32 bit integers
+        let snowflake: u64 = 1_234_567_890_123_456_789;
+        let encoded = encode(&json!(snowflake));
+        assert_eq!(
+            vec![131, 110, 8, 0, 0x15, 0x81, 0xe9, 0x7d, 0xf4, 0x10, 0x22, 0x11],
+            encoded
+        );
+        assert_eq!(json!(snowflake), decode(&encoded).unwrap());
+
+        assert_eq!(
+            vec![131, 110, 5, 1, 0, 0, 0, 0, 1],
+            encode(&json!(-(1i64 << 32)))
+        );
+        assert_eq!(
+            json!(-(1i64 << 32)),
+            decode(&[131, 110, 5, 1, 0, 0, 0, 0, 1]).unwrap()
+        );
+        assert_eq!(
+            Err(EtfError::IntegerTooLarge),
+            decode(&[131, 110, 9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1])
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let value = json!({
+            "op": 2,
+            "d": {
+                "token": "TOKEN",
+                "intents": 513,
+                "shard": [0, 1],
+                "large_threshold": 50,
+                "compress": false,
+                "presence": null,
+                "ratio": 0.5,
+                "negative": -5,
+                "empty": [],
+                "nested": {"list": [{"a": "b"}, null, true]},
+            }
+        });
+
+        assert_eq!(value, decode(&encode(&value)).unwrap());
+    }
+
+    #[test]
+    fn decode_terms() {
+        // {1, "ab"} as tuple of a small integer and a string
+        assert_eq!(
+            json!([1, "ab"]),
+            decode(&[131, 104, 2, 97, 1, 107, 0, 2, b'a', b'b']).unwrap()
+        );
+        // negative integer
+        assert_eq!(json!(-2), decode(&[131, 98, 255, 255, 255, 254]).unwrap());
+        // float
+        let mut float = vec![131, 70];
+        float.extend_from_slice(&1.5f64.to_be_bytes());
+        assert_eq!(json!(1.5), decode(&float).unwrap());
+        // utf8 atoms
+        assert_eq!(json!("ü"), decode(&[131, 119, 2, 0xc3, 0xbc]).unwrap());
+        assert_eq!(
+            json!(false),
+            decode(&[131, 115, 5, b'f', b'a', b'l', b's', b'e']).unwrap()
+        );
+    }
+
+    #[test]
+    fn invalid_input() {
+        assert_eq!(Err(EtfError::InvalidVersion(130)), decode(&[130, 97, 1]));
+        assert_eq!(
+            Err(EtfError::UnexpectedEof),
+            decode(&[131, 109, 0, 0, 0, 5, b'a'])
+        );
+        assert_eq!(
+            Err(EtfError::UnexpectedEof),
+            decode(&[131, 116, 255, 255, 255, 255])
+        );
+        assert_eq!(
+            Err(EtfError::UnsupportedTag(80)),
+            decode(&[131, 80, 0, 0, 0, 0])
+        );
+        assert_eq!(Err(EtfError::TrailingData), decode(&[131, 97, 1, 97, 2]));
+        assert_eq!(
+            Err(EtfError::InvalidUtf8),
+            decode(&[131, 109, 0, 0, 0, 1, 0xff])
+        );
+    }
+}
@@ -0,0 +1,276 @@
+//! Groups of shards
+//!
+//! A [`ShardGroup`] owns the contexts of multiple shards and coordinates their identifies with an
+//! [`IdentifyQueue`].
+//!
+//! <https://discord.com/developers/docs/topics/gateway#sharding-max-concurrency>
+
+use super::{Config, GatewayCommand, GatewayContext, IdentifyQueue, ShardId};
+use crate::Error;
+use std::{collections::BTreeMap, time::Instant};
+use twilight_model::gateway::event::{Event, GatewayEvent};
+
+/// Sans-IO group of shards
+///
+/// Inbound messages are routed to the context of their shard, outgoing commands and events are
+/// tagged with the shard they belong to. The application is responsible for one socket per shard.
+///
+/// # Example
+/// ```no_run
+/// use discord::{
+///     proto::{Config, GatewayCommand, ShardGroup, ShardId},
+///     model::gateway::{Intents, event::GatewayEvent},
+/// };
+/// # fn send_to_socket(shard: ShardId, cmd: GatewayCommand) {}
+/// let config = Config::new("<token>", Intents::empty());
+/// let shards = ShardId::range(0..4, 4).unwrap();
+/// let mut group = ShardGroup::new(config, shards, 1);
+///
+/// // route received events to the shard of the socket
+/// let shard = ShardId::new(0, 4).unwrap();
+/// group.recv_event(shard, GatewayEvent::Hello(41250)).unwrap();
+///
+/// while let Some((shard, cmd)) = group.send() {
+///     send_to_socket(shard, cmd);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ShardGroup {
+    /// contexts of the shards in this group
+    pub shards: BTreeMap<ShardId, GatewayContext>,
+    /// queue that decides when the shards are allowed to identify
+    pub identify_queue: IdentifyQueue,
+}
+
+impl ShardGroup {
+    /// Create a group of contexts for the shards
+    ///
+    /// `max_concurrency` is returned by the `/gateway/bot` endpoint.
+    pub fn new<C, I>(config: C, shards: I, max_concurrency: u64) -> Self
+    where
+        C: Into<Config>,
+        I: IntoIterator<Item = ShardId>,
+    {
+        ShardGroup::with_identify_queue(config, shards, IdentifyQueue::new(max_concurrency))
+    }
+
+    /// Create a group of contexts for the shards that identify according to the queue
+    ///
+    /// The queue can be shared with other groups of the same bot.
+    pub fn with_identify_queue<C, I>(config: C, shards: I, identify_queue: IdentifyQueue) -> Self
+    where
+        C: Into<Config>,
+        I: IntoIterator<Item = ShardId>,
+    {
+        let config = config.into();
+
+        let shards = shards
+            .into_iter()
+            .map(|shard| {
+                let mut ctx = GatewayContext::new(config.clone().shard(shard));
+                ctx.gate_identify(true);
+                ctx.queue_events(true);
+                (shard, ctx)
+            })
+            .collect();
+
+        ShardGroup {
+            shards,
+            identify_queue,
+        }
+    }
+
+    /// Returns the ids of the shards in this group
+    pub fn shard_ids(&self) -> impl Iterator<Item = ShardId> + '_ {
+        self.shards.keys().copied()
+    }
+
+    /// Returns the context of a shard
+    pub fn shard(&self, shard: ShardId) -> Option<&GatewayContext> {
+        self.shards.get(&shard)
+    }
+
+    /// Returns the context of a shard
+    pub fn shard_mut(&mut self, shard: ShardId) -> Option<&mut GatewayContext> {
+        self.shards.get_mut(&shard)
+    }
+
+    fn get_mut(&mut self, shard: ShardId) -> Result<&mut GatewayContext, Error> {
+        self.shards
+            .get_mut(&shard)
+            .ok_or(Error::UnknownShard(shard))
+    }
+
+    /// Processes an event received by a shard
+    pub fn recv_event(&mut self, shard: ShardId, event: GatewayEvent) -> Result<(), Error> {
+        self.get_mut(shard)?.recv_event(event);
+        self.allow_identifies(Instant::now());
+        Ok(())
+    }
+
+    /// Processes a JSON message received by a shard
+    #[cfg(feature = "json")]
+    pub fn recv_json(&mut self, shard: ShardId, input: &str) -> Result<(), Error> {
+        self.get_mut(shard)?.recv_json_event(input)?;
+        self.allow_identifies(Instant::now());
+        Ok(())
+    }
+
+    /// Processes a binary message received by a shard
+    #[cfg(feature = "json")]
+    pub fn recv_binary(&mut self, shard: ShardId, frame: &[u8]) -> Result<(), Error> {
+        self.get_mut(shard)?.recv_binary_event(frame)?;
+        self.allow_identifies(Instant::now());
+        Ok(())
+    }
+
+    /// Processes a close code received by a shard
+    pub fn recv_close_code(&mut self, shard: ShardId, code: u16) -> Result<(), Error> {
+        self.get_mut(shard)?.recv_close_code(code);
+        Ok(())
+    }
+
+    /// Notifies the shard that its socket (re)connected to the gateway
+    pub fn socket_connected(&mut self, shard: ShardId) -> Result<(), Error> {
+        self.get_mut(shard)?.socket_connected();
+        Ok(())
+    }
+
+    /// Returns the shards whose sockets have to be reconnected
+    pub fn reconnects(&self) -> impl Iterator<Item = ShardId> + '_ {
+        self.shards
+            .iter()
+            .filter(|(_, ctx)| ctx.should_reconnect())
+            .map(|(shard, _)| *shard)
+    }
+
+    /// Returns the next event of any shard
+    pub fn poll_event(&mut self) -> Option<(ShardId, Event)> {
+        self.shards
+            .iter_mut()
+            .find_map(|(shard, ctx)| ctx.poll_event().map(|event| (*shard, event)))
+    }
+
+    /// Returns the next command of any shard that has to be sent to the gateway
+    pub fn send(&mut self) -> Option<(ShardId, GatewayCommand)> {
+        self.shards
+            .iter_mut()
+            .find_map(|(shard, ctx)| ctx.send().map(|cmd| (*shard, cmd)))
+    }
+
+    /// Returns the next command of any shard serialized in JSON
+    #[cfg(feature = "json")]
+    pub fn send_json(&mut self) -> Option<(ShardId, String)> {
+        self.shards
+            .iter_mut()
+            .find_map(|(shard, ctx)| ctx.send_json().map(|cmd| (*shard, cmd)))
+    }
+
+    /// Returns the point in time at which [`on_timeout()`] should be called next
+    ///
+    /// [`on_timeout()`]: ShardGroup::on_timeout
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let identifies = self
+            .shards
+            .iter()
+            .filter(|(_, ctx)| ctx.wants_identify())
+            .map(|(shard, _)| self.identify_queue.available_at(*shard, now));
+
+        self.shards
+            .values()
+            .filter_map(GatewayContext::poll_timeout)
+            .chain(identifies)
+            .min()
+    }
+
+    /// Advances the timers of all shards to `now` and allows waiting shards to identify
+    pub fn on_timeout(&mut self, now: Instant) {
+        for ctx in self.shards.values_mut() {
+            ctx.on_timeout(now);
+        }
+        self.allow_identifies(now);
+    }
+
+    /// Allows the waiting shards to identify if the identify queue permits it
+    fn allow_identifies(&mut self, now: Instant) {
+        for (shard, ctx) in self.shards.iter_mut() {
+            if ctx.wants_identify() && self.identify_queue.try_acquire(*shard, now).is_ok() {
+                log::debug!("allowing shard {} to identify", shard);
+                ctx.allow_identify();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::identify::IDENTIFY_INTERVAL;
+    use twilight_model::gateway::Intents;
+
+    fn identifying(group: &mut ShardGroup) -> Vec<u64> {
+        std::iter::from_fn(|| group.send())
+            .filter(|(_, cmd)| matches!(cmd, GatewayCommand::Identify(_)))
+            .map(|(shard, _)| shard.index())
+            .collect()
+    }
+
+    #[test]
+    fn routing() {
+        let shards = ShardId::range(0..2, 2).unwrap();
+        let mut group = ShardGroup::new(("TOKEN", Intents::empty()), shards, 1);
+
+        let first = ShardId::new(0, 2).unwrap();
+        let second = ShardId::new(1, 2).unwrap();
+        group
+            .recv_event(second, GatewayEvent::Hello(41250))
+            .unwrap();
+        assert!(!group.shard(first).unwrap().wants_identify());
+        assert_eq!(
+            Some((second, Event::GatewayHello(41250))),
+            group.poll_event()
+        );
+
+        // identifies contain the shard of the context
+        let (shard, cmd) = group.send().unwrap();
+        assert_eq!(second, shard);
+        match cmd {
+            GatewayCommand::Identify(identify) => assert_eq!(Some([1, 2]), identify.d.shard),
+            cmd => panic!("unexpected command {:?}", cmd),
+        }
+
+        let unknown = ShardId::new(2, 3).unwrap();
+        assert!(matches!(
+            group.recv_event(unknown, GatewayEvent::Hello(41250)),
+            Err(Error::UnknownShard(_))
+        ));
+
+        group.recv_close_code(second, 1000).unwrap();
+        assert_eq!(vec![second], group.reconnects().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn identify_buckets() {
+        let shards = ShardId::range(0..5, 5).unwrap();
+        let mut group = ShardGroup::new(("TOKEN", Intents::empty()), shards.clone(), 2);
+
+        for shard in shards {
+            group.recv_event(shard, GatewayEvent::Hello(41250)).unwrap();
+        }
+
+        // one shard per bucket identifies at a time
+        assert_eq!(vec![0, 1], identifying(&mut group));
+        let deadline = group.poll_timeout().unwrap();
+        assert!(deadline <= Instant::now() + IDENTIFY_INTERVAL);
+
+        group.on_timeout(Instant::now());
+        assert!(identifying(&mut group).is_empty());
+
+        group.on_timeout(Instant::now() + IDENTIFY_INTERVAL);
+        assert_eq!(vec![2, 3], identifying(&mut group));
+
+        group.on_timeout(Instant::now() + IDENTIFY_INTERVAL * 2);
+        assert_eq!(vec![4], identifying(&mut group));
+    }
+}
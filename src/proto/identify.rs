@@ -0,0 +1,233 @@
+//! Identify rate limiting
+//!
+//! The gateway limits how often a bot may identify:
+//! - shards are put into buckets by `shard_id % max_concurrency` and each bucket may only
+//!   identify once every 5 seconds
+//! - a bot may only start a limited number of sessions per day, the remaining number and the
+//!   time until the limit resets are returned by `/gateway/bot`
+//!
+//! <https://discord.com/developers/docs/topics/gateway#session-start-limit-object>
+
+use super::ShardId;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use twilight_model::gateway::SessionStartLimit;
+
+/// Time after which a bucket is allowed to identify again
+pub(crate) const IDENTIFY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time after which the session start limit resets if it is not known
+const SESSION_START_RESET: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Queue that decides when shards are allowed to identify
+///
+/// The queue does not have access to a clock, the current time is passed to every call instead.
+/// Cloned queues share their state, so a queue can be shared by all shards of a process.
+///
+/// # Example
+/// ```
+/// # use discord::proto::{IdentifyQueue, ShardId};
+/// # use std::time::{Duration, Instant};
+/// let queue = IdentifyQueue::new(2);
+/// let now = Instant::now();
+///
+/// assert_eq!(Ok(()), queue.try_acquire(ShardId::new(0, 4).unwrap(), now));
+/// assert_eq!(Ok(()), queue.try_acquire(ShardId::new(1, 4).unwrap(), now));
+///
+/// // shard 2 is in the same bucket as shard 0
+/// let shard = ShardId::new(2, 4).unwrap();
+/// assert_eq!(Err(now + Duration::from_secs(5)), queue.try_acquire(shard, now));
+/// ```
+#[derive(Debug, Clone)]
+pub struct IdentifyQueue {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// point in time at which each bucket is allowed to identify again
+    buckets: Vec<Option<Instant>>,
+    /// remaining number of session starts, `None` if unknown
+    remaining: Option<u64>,
+    /// total number of session starts per reset
+    total: u64,
+    /// point in time at which the session start limit resets
+    reset_at: Option<Instant>,
+}
+
+impl IdentifyQueue {
+    /// Create a queue with `max_concurrency` buckets and an unknown session start limit
+    pub fn new(max_concurrency: u64) -> Self {
+        let max_concurrency = max_concurrency.max(1);
+        IdentifyQueue {
+            inner: Arc::new(Mutex::new(Inner {
+                buckets: vec![None; max_concurrency as usize],
+                remaining: None,
+                total: 0,
+                reset_at: None,
+            })),
+        }
+    }
+
+    /// Create a queue from the session start limit returned by `/gateway/bot`
+    pub fn with_limit(limit: &SessionStartLimit, now: Instant) -> Self {
+        let queue = IdentifyQueue::new(limit.max_concurrency);
+        queue.update_limit(limit, now);
+        queue
+    }
+
+    /// Update the session start limit, e.g. after requesting `/gateway/bot` again
+    pub fn update_limit(&self, limit: &SessionStartLimit, now: Instant) {
+        let mut inner = self.lock();
+        inner.remaining = Some(limit.remaining);
+        inner.total = limit.total;
+        inner.reset_at = Some(now + Duration::from_millis(limit.reset_after));
+    }
+
+    /// Returns the number of buckets that are allowed to identify at the same time
+    pub fn max_concurrency(&self) -> u64 {
+        self.lock().buckets.len() as u64
+    }
+
+    /// Returns the remaining number of session starts, `None` if it is unknown
+    pub fn remaining(&self) -> Option<u64> {
+        self.lock().remaining
+    }
+
+    /// Returns the point in time at which the shard is allowed to identify
+    pub fn available_at(&self, shard: ShardId, now: Instant) -> Instant {
+        let mut inner = self.lock();
+        inner.reset(now);
+        inner.available_at(shard).map_or(now, |at| at.max(now))
+    }
+
+    /// Acquire the permission for the shard to identify
+    ///
+    /// Returns the point in time at which the shard should try again if it is not allowed to
+    /// identify yet.
+    pub fn try_acquire(&self, shard: ShardId, now: Instant) -> Result<(), Instant> {
+        let mut inner = self.lock();
+        inner.reset(now);
+
+        if let Some(available_at) = inner.available_at(shard).filter(|at| *at > now) {
+            log::debug!(
+                "shard {} has to wait {:?} to identify",
+                shard,
+                available_at - now
+            );
+            return Err(available_at);
+        }
+
+        let bucket = inner.bucket(shard);
+        inner.buckets[bucket] = Some(now + IDENTIFY_INTERVAL);
+        if let Some(remaining) = inner.remaining.as_mut() {
+            *remaining = remaining.saturating_sub(1);
+            if inner.reset_at.is_none() {
+                inner.reset_at = Some(now + SESSION_START_RESET);
+            }
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        // the state stays consistent even if another thread panicked
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Inner {
+    fn bucket(&self, shard: ShardId) -> usize {
+        (shard.index() % self.buckets.len() as u64) as usize
+    }
+
+    /// Resets the session start limit if the reset time has passed
+    fn reset(&mut self, now: Instant) {
+        if let (Some(_), Some(reset_at)) = (self.remaining, self.reset_at) {
+            if reset_at <= now {
+                log::debug!("session start limit reset: total= {}", self.total);
+                self.remaining = Some(self.total);
+                self.reset_at = None;
+            }
+        }
+    }
+
+    /// Returns the point in time at which the shard is allowed to identify, `None` if it is
+    /// allowed right away
+    fn available_at(&self, shard: ShardId) -> Option<Instant> {
+        let bucket = self.buckets[self.bucket(shard)];
+        let limit = match self.remaining {
+            Some(0) => self.reset_at,
+            _ => None,
+        };
+
+        bucket.into_iter().chain(limit).max()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shard(index: u64) -> ShardId {
+        ShardId::new(index, 16).unwrap()
+    }
+
+    #[test]
+    fn buckets() {
+        let queue = IdentifyQueue::new(4);
+        let now = Instant::now();
+
+        for index in 0..4 {
+            assert_eq!(Ok(()), queue.try_acquire(shard(index), now));
+        }
+        assert_eq!(
+            Err(now + IDENTIFY_INTERVAL),
+            queue.try_acquire(shard(4), now)
+        );
+        assert_eq!(now + IDENTIFY_INTERVAL, queue.available_at(shard(7), now));
+
+        let later = now + IDENTIFY_INTERVAL;
+        assert_eq!(Ok(()), queue.try_acquire(shard(4), later));
+        assert_eq!(Ok(()), queue.try_acquire(shard(5), later));
+        assert_eq!(
+            Err(later + IDENTIFY_INTERVAL),
+            queue.try_acquire(shard(8), later)
+        );
+    }
+
+    #[test]
+    fn session_start_limit() {
+        let now = Instant::now();
+        let limit = SessionStartLimit {
+            max_concurrency: 1,
+            remaining: 2,
+            reset_after: 60_000,
+            total: 1000,
+        };
+        let queue = IdentifyQueue::with_limit(&limit, now);
+        let reset_at = now + Duration::from_secs(60);
+
+        assert_eq!(Ok(()), queue.try_acquire(shard(0), now));
+        let now = now + IDENTIFY_INTERVAL;
+        assert_eq!(Ok(()), queue.try_acquire(shard(1), now));
+        assert_eq!(Some(0), queue.remaining());
+
+        // the remaining session starts are exhausted until the limit resets
+        let now = now + IDENTIFY_INTERVAL;
+        assert_eq!(Err(reset_at), queue.try_acquire(shard(2), now));
+        assert_eq!(Ok(()), queue.try_acquire(shard(2), reset_at));
+        assert_eq!(Some(999), queue.remaining());
+    }
+
+    #[test]
+    fn shared() {
+        let queue = IdentifyQueue::new(1);
+        let other = queue.clone();
+        let now = Instant::now();
+
+        assert_eq!(Ok(()), queue.try_acquire(shard(0), now));
+        assert!(other.try_acquire(shard(1), now).is_err());
+    }
+}
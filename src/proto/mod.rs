@@ -33,6 +33,7 @@
 //! // As soon as the connection receives the correct `Hello` message from the gateway it will
 //! // automatically initialize the connection.
 //! let mut ctx = GatewayContext::new(Config::new("<token>", Intents::all()));
+//! ctx.queue_events(true);
 //!
 //! loop {
 //!     // reconnect the socket if necessary
@@ -43,8 +44,13 @@
 //!     // wait for a GatewayEvent to be received from the socket
 //!     let event = recv_from_socket();
 //!
-//!     // pass the received event to the context for processing
-//!     ctx.recv(&event);
+//!     // pass the received event to the context for processing and queue it
+//!     ctx.recv_event(event);
+//!
+//!     // pull out the processed events and any events generated by the context
+//!     while let Some(event) = ctx.poll_event() {
+//!         println!("received event: {:?}", event.kind());
+//!     }
 //!
 //!     // The context will generate messages that need to be sent to the server
 //!     for cmd in ctx.send_iter() {
@@ -54,10 +60,23 @@
 //! ```
 //!
 //! # Heartbeating
-//! The application is responsible to maintain a heartbeat timer and queue heartbeat packets at the
-//! correct time.
+//! The context keeps track of the heartbeat timer by itself but does not have access to a clock.
+//! The application is responsible to wake the context up once the deadline returned by
+//! [`poll_timeout()`] has passed and call [`on_timeout()`], which queues heartbeats as necessary.
+//! ```no_run
+//! use std::time::Instant;
+//! use discord::{proto::GatewayContext, model::gateway::Intents};
+//! # fn sleep_until(deadline: Instant) {}
+//! let mut ctx = GatewayContext::new(("<token>", Intents::empty()));
+//!
+//! if let Some(deadline) = ctx.poll_timeout() {
+//!     sleep_until(deadline);
+//!     ctx.on_timeout(Instant::now());
+//! }
+//! ```
 //!
-//! Get the heartbeat interaval and queue a heartbeat:
+//! Applications that want to maintain their own heartbeat timer can get the heartbeat interval
+//! and queue a heartbeat manually:
 //! ```
 //! use discord::{proto::GatewayContext, model::gateway::Intents};
 //! let mut ctx = GatewayContext::new(("<token>", Intents::empty()));
@@ -67,24 +86,77 @@
 //! ctx.queue_heartbeat();
 //! ```
 //!
+//! # Transport compression
+//! Transport compression can be enabled with [`Config::compression`] (requires the `zlib` or
+//! `zstd` feature). The gateway will then send compressed binary messages that have to be passed to
+//! [`recv_binary()`]. The application has to call [`socket_connected()`] every time the socket
+//! (re)connects so that the compression stream is reset.
+//! The gateway url has to contain the correct query parameters which can be added with
+//! [`Config::connection_url`].
+//!
+//! # Encoding
+//! Messages are JSON encoded by default. With the `etf` feature the gateway can be asked to use
+//! the Erlang term format instead by setting [`Config::encoding`] to [`Encoding::Etf`].
+//! ETF messages are received as binary messages and have to be passed to [`recv_binary()`]
+//! while commands are encoded with [`send_etf()`] or [`send_iter_etf()`].
+//!
 //! [`recv()`]: GatewayContext::recv
 //! [`recv_json()`]: GatewayContext::recv_json
 //! [`send_iter()`]: GatewayContext::send_iter
 //! [`send()`]: GatewayContext::send
+//! [`send_etf()`]: GatewayContext::send_etf
+//! [`send_iter_etf()`]: GatewayContext::send_iter_etf
+//! [`recv_binary()`]: GatewayContext::recv_binary
+//! [`socket_connected()`]: GatewayContext::socket_connected
+//! [`poll_timeout()`]: GatewayContext::poll_timeout
+//! [`on_timeout()`]: GatewayContext::on_timeout
 
-use crate::error::CloseCode;
+use crate::error::{CloseAction, CloseCode};
 use serde::Serialize;
-use std::collections::VecDeque;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
 use twilight_model::gateway::{
-    event::{DispatchEvent, GatewayEvent},
+    event::{
+        shard::{Connected, Disconnected, Identifying, Reconnecting, Resuming},
+        DispatchEvent, Event, GatewayEvent,
+    },
     payload::outgoing::{
         identify::IdentifyInfo, Heartbeat, Identify, RequestGuildMembers, Resume, UpdatePresence,
         UpdateVoiceState,
     },
 };
 
+mod backoff;
+mod compression;
 mod config;
+#[cfg(feature = "etf")]
+pub mod etf;
+mod group;
+mod identify;
+mod ratelimit;
+mod rng;
+mod session;
+mod shard;
+mod stats;
+mod transition;
+pub use backoff::BackoffPolicy;
+pub use compression::Decompressor;
+#[cfg(feature = "zlib")]
+pub use compression::Inflater;
+#[cfg(feature = "zstd")]
+pub use compression::ZstdDecoder;
 pub use config::*;
+pub use group::ShardGroup;
+pub use identify::IdentifyQueue;
+pub use ratelimit::RateLimiter;
+pub use session::SessionState;
+pub use shard::ShardId;
+pub use stats::Stats;
+pub use transition::{Transition, TransitionCause};
+
+use rng::Rng;
 
 #[allow(missing_docs)]
 #[derive(Debug, PartialEq, Eq, Serialize)]
@@ -98,8 +170,37 @@ pub enum GatewayCommand {
     UpdateVoiceState(UpdateVoiceState),
 }
 
+impl GatewayCommand {
+    /// Commands that are required to keep the connection alive.
+    ///
+    /// These are sent before any other queued command and may use the reserved part of the
+    /// rate limit.
+    fn is_priority(&self) -> bool {
+        matches!(
+            self,
+            GatewayCommand::Heartbeat(_) | GatewayCommand::Identify(_) | GatewayCommand::Resume(_)
+        )
+    }
+}
+
 const SEND_QUEUE_SIZE: usize = 1;
 
+/// Bounds of the random delay before identifying again after an invalid session
+const INVALID_SESSION_DELAY_MIN: Duration = Duration::from_secs(1);
+const INVALID_SESSION_DELAY_MAX: Duration = Duration::from_secs(5);
+
+/// Close code that ends the session
+const CLOSE_NORMAL: u16 = 1000;
+
+/// Close code that keeps the session resumable, any code except 1000 and 1001 would do
+const CLOSE_RESUMABLE: u16 = 4000;
+
+/// Close code of a connection that was lost without receiving a close frame
+pub(crate) const CLOSE_ABNORMAL: u16 = 1006;
+
+/// Number of unpolled state transitions that are kept, older transitions are dropped
+const TRANSITION_QUEUE_SIZE: usize = 16;
+
 /// Discord gateway context
 ///
 /// Context for a given discord gateway connection.
@@ -119,6 +220,35 @@ pub struct GatewayContext {
     pub state: State,
     pub socket_closed: bool,
     pub resume_url: String,
+    /// whether the last heartbeat was acknowledged by the gateway
+    pub heartbeat_acked: bool,
+    /// point in time at which the next heartbeat is due
+    pub next_heartbeat: Option<Instant>,
+    /// delay of the first heartbeat after receiving `Hello`
+    pub first_heartbeat_delay: Duration,
+    /// rate limiter of the current connection
+    pub ratelimiter: RateLimiter,
+    /// statistics about the connection
+    pub stats: Stats,
+    /// the last state transitions that have not been polled yet
+    pub transitions: VecDeque<Transition>,
+    /// processed events that have not been polled yet
+    pub recv_queue: VecDeque<Event>,
+    /// whether processed events are queued, see [`queue_events()`](GatewayContext::queue_events)
+    pub events_queued: bool,
+    /// point in time before which the socket should not be reconnected
+    pub reconnect_not_before: Option<Instant>,
+    /// whether identify commands are held back until they are allowed
+    pub identify_gated: bool,
+    /// whether an identify command is held back
+    pub identify_pending: bool,
+    /// number of consecutive reconnect attempts since the last stable connection
+    pub reconnect_attempts: u32,
+    /// point in time at which the socket connected
+    pub connected_at: Option<Instant>,
+    /// decompressor for the transport compression of the current connection
+    pub decompressor: Option<Decompressor>,
+    rng: Rng,
 }
 
 /// State of the gateway connection
@@ -155,6 +285,17 @@ impl GatewayContext {
         self.heartbeat_interval
     }
 
+    /// Returns the delay between receiving `Hello` and sending the first heartbeat.
+    ///
+    /// The gateway expects the first heartbeat after `heartbeat_interval * jitter` where jitter is
+    /// a random value between 0 and 1, so that not all clients send heartbeats at the same time
+    /// after a mass reconnect.
+    ///
+    /// <https://discord.com/developers/docs/topics/gateway#sending-heartbeats>
+    pub fn first_heartbeat_delay(&self) -> Duration {
+        self.first_heartbeat_delay
+    }
+
     /// Returns the url that should be used for reconnects
     ///
     /// <https://discord.com/developers/docs/topics/gateway#preparing-to-resume>
@@ -170,8 +311,12 @@ impl GatewayContext {
         let config: Config = config.into();
         assert!(!config.token.is_empty(), "token cannot be empty");
 
+        let rng = match config.rng_seed {
+            Some(seed) => Rng::new(seed),
+            None => Rng::from_entropy(),
+        };
+
         GatewayContext {
-            config,
             seq: 0,
             heartbeat_interval: 0,
             send_queue: VecDeque::with_capacity(SEND_QUEUE_SIZE),
@@ -179,11 +324,82 @@ impl GatewayContext {
             session_id: String::new(),
             socket_closed: false,
             resume_url: String::new(),
+            heartbeat_acked: true,
+            next_heartbeat: None,
+            first_heartbeat_delay: Duration::ZERO,
+            ratelimiter: RateLimiter::default(),
+            stats: Stats::default(),
+            transitions: VecDeque::new(),
+            recv_queue: VecDeque::new(),
+            events_queued: false,
+            reconnect_not_before: None,
+            identify_gated: false,
+            identify_pending: false,
+            reconnect_attempts: 0,
+            connected_at: None,
+            decompressor: config.compression.map(Decompressor::new),
+            rng,
+            config,
+        }
+    }
+
+    /// Create a new GatewayContext that resumes an existing session
+    ///
+    /// The context starts in the [`State::Resume`] state and resumes the session as soon as it
+    /// receives the first `Hello` event. The socket should be connected to the
+    /// [`resume_gateway_url()`].
+    ///
+    /// # Example
+    /// ```
+    /// # use discord::proto::{GatewayContext, SessionState, State};
+    /// # use twilight_model::gateway::Intents;
+    /// let session = SessionState {
+    ///     session_id: "<session_id>".to_string(),
+    ///     seq: 42,
+    ///     resume_url: "wss://gateway-us-east1-b.discord.gg".to_string(),
+    /// };
+    ///
+    /// let conn = GatewayContext::resume(("TOKEN", Intents::empty()), session);
+    /// assert_eq!(State::Resume, *conn.state());
+    /// assert_eq!("wss://gateway-us-east1-b.discord.gg", conn.resume_gateway_url());
+    /// ```
+    ///
+    /// [`resume_gateway_url()`]: GatewayContext::resume_gateway_url
+    pub fn resume<C>(config: C, session: SessionState) -> Self
+    where
+        C: Into<Config>,
+    {
+        let mut ctx = GatewayContext::new(config);
+        ctx.session_id = session.session_id;
+        ctx.seq = session.seq;
+        ctx.resume_url = session.resume_url;
+        ctx.state = State::Resume;
+        ctx
+    }
+
+    /// Returns the state needed to resume the current session.
+    ///
+    /// Returns `None` if no session has been established yet.
+    pub fn session(&self) -> Option<SessionState> {
+        if self.session_id.is_empty() {
+            return None;
         }
+
+        Some(SessionState {
+            session_id: self.session_id.clone(),
+            seq: self.seq,
+            resume_url: self.resume_url.clone(),
+        })
     }
 
     /// Queue a heartbeat packet to be sent to the gateway
     ///
+    /// If the previous heartbeat was not acknowledged by the gateway the connection is considered
+    /// to be a zombie. No heartbeat will be queued and the context switches into a reconnecting
+    /// state instead, so that [`should_reconnect()`] returns `true`.
+    ///
+    /// <https://discord.com/developers/docs/topics/gateway#heartbeat-interval-example-heartbeat-ack>
+    ///
     /// # Example
     /// ```
     /// # use discord::proto::{GatewayContext, GatewayCommand};
@@ -192,13 +408,84 @@ impl GatewayContext {
     /// conn.queue_heartbeat();
     /// assert_eq!(Some(GatewayCommand::Heartbeat(Heartbeat::new(0))), conn.send());
     /// ```
+    ///
+    /// [`should_reconnect()`]: GatewayContext::should_reconnect
     pub fn queue_heartbeat(&mut self) {
+        if !self.heartbeat_acked {
+            log::warn!("heartbeat was not acknowledged, reconnecting zombied connection");
+            self.set_state(self.resume_state(), TransitionCause::Zombie);
+            self.next_heartbeat = None;
+            return;
+        }
+
+        self.push_heartbeat();
+    }
+
+    fn push_identify(&mut self) {
+        self.identify_pending = false;
+        self.send_queue
+            .push_back(GatewayCommand::Identify(Identify::new(IdentifyInfo {
+                compress: false,
+                token: self.config.token.clone(),
+                shard: Some(self.config.shard.into()),
+                intents: self.config.intents,
+                large_threshold: self.config.large_threshold,
+                presence: self.config.presence.clone(),
+                properties: self.config.identify_properties.clone(),
+            })));
+    }
+
+    /// Hold back identify commands until they are allowed with [`allow_identify()`].
+    ///
+    /// Shards of the same bot have to coordinate their identifies (see [`ShardGroup`]), so the
+    /// context must not identify on its own.
+    ///
+    /// # Example
+    /// ```
+    /// # use discord::proto::{GatewayContext, GatewayCommand};
+    /// # use twilight_model::gateway::{Intents, event::GatewayEvent};
+    /// # let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+    /// conn.gate_identify(true);
+    /// conn.recv(&GatewayEvent::Hello(41250));
+    /// assert!(conn.wants_identify());
+    /// assert_eq!(None, conn.send());
+    ///
+    /// conn.allow_identify();
+    /// assert!(matches!(conn.send(), Some(GatewayCommand::Identify(_))));
+    /// ```
+    ///
+    /// [`allow_identify()`]: GatewayContext::allow_identify
+    pub fn gate_identify(&mut self, gated: bool) {
+        self.identify_gated = gated;
+    }
+
+    /// Returns true if an identify is held back and waits to be allowed
+    pub fn wants_identify(&self) -> bool {
+        self.identify_pending
+    }
+
+    /// Queues the identify command that was held back
+    ///
+    /// Does nothing if no identify is held back.
+    pub fn allow_identify(&mut self) {
+        if self.identify_pending {
+            self.push_identify();
+        }
+    }
+
+    fn push_heartbeat(&mut self) {
+        self.heartbeat_acked = false;
         self.send_queue
             .push_back(GatewayCommand::Heartbeat(Heartbeat::new(self.seq)))
     }
 
     /// Process a close code received from the gateway websocket connection
     ///
+    /// Depending on the [`CloseAction`] of the code the session is resumed, a new session is
+    /// identified or the connection fails.
+    ///
+    /// [`CloseAction`]: crate::error::CloseAction
+    ///
     /// # Example
     /// ```
     /// # use discord::{proto::GatewayContext, error::CloseCode};
@@ -209,8 +496,13 @@ impl GatewayContext {
     /// assert!(conn.should_reconnect());
     /// assert_eq!(None, conn.failed());
     ///
+    /// // the session timed out, a new session has to be identified
+    /// conn.recv_close_code(4009u16);
+    /// assert!(conn.should_reconnect());
+    /// assert_eq!(None, conn.session());
+    ///
     /// // authentication failed code
-    /// conn.recv_close_code(4005u16);
+    /// conn.recv_close_code(4004u16);
     /// assert!(!conn.should_reconnect());
     /// assert_eq!(Some(CloseCode::AuthenticationFailed), conn.failed());
     /// ```
@@ -218,18 +510,263 @@ impl GatewayContext {
     where
         T: Into<u16>,
     {
-        let code = CloseCode::from(code.into());
+        let raw_code = code.into();
+        let code = CloseCode::from(raw_code);
         log::debug!("recv_close_code: {}", code);
         self.socket_closed = true;
+        self.queue_event(Event::ShardDisconnected(Disconnected {
+            code: Some(raw_code),
+            reason: None,
+            shard_id: self.config.shard.index(),
+        }));
+        self.next_heartbeat = None;
+        self.reset_compression();
 
-        self.state = if code.is_recoverable() {
-            State::Resume
+        let state = match code.action() {
+            CloseAction::Resume => self.resume_state(),
+            CloseAction::Reidentify => {
+                self.clear_session();
+                State::Reconnect
+            }
+            CloseAction::Fatal => State::Failed(code),
+        };
+        self.set_state(state, TransitionCause::Closed(code));
+    }
+
+    /// Notifies the context that the underlying socket (re)connected to the gateway.
+    ///
+    /// Resets the state of the transport compression, since every connection uses its own
+    /// compression stream.
+    pub fn socket_connected(&mut self) {
+        log::debug!("socket connected");
+        self.socket_closed = false;
+        self.reconnect_not_before = None;
+        self.connected_at = Some(Instant::now());
+        self.reset_compression();
+    }
+
+    /// Closes the connection on behalf of the application.
+    ///
+    /// Returns the close code the socket has to be closed with. If `resumable` is false the code
+    /// is 1000, which ends the session so that the bot appears offline right away. Otherwise the
+    /// session stays resumable with the state returned by [`session()`] until the resume window
+    /// expires.
+    ///
+    /// Queued commands are dropped and the context does not reconnect afterwards.
+    ///
+    /// [`session()`]: GatewayContext::session
+    pub fn close(&mut self, resumable: bool) -> u16 {
+        let code = if resumable {
+            CLOSE_RESUMABLE
         } else {
-            State::Failed(code)
+            self.clear_session();
+            CLOSE_NORMAL
         };
+        log::debug!("closing connection: code= {}", code);
+
+        self.send_queue.clear();
+        self.next_heartbeat = None;
+        self.queue_event(Event::ShardDisconnected(Disconnected {
+            code: Some(code),
+            reason: None,
+            shard_id: self.config.shard.index(),
+        }));
+        self.set_state(State::Closed, TransitionCause::Shutdown);
+        code
+    }
+
+    /// Returns the state to resume the session in, or to identify a new session if there is none
+    fn resume_state(&self) -> State {
+        if self.session_id.is_empty() {
+            State::Reconnect
+        } else {
+            State::Resume
+        }
+    }
+
+    /// Clears the current session, so that the next connection identifies with a new session.
+    fn clear_session(&mut self) {
+        log::info!("session invalidated: session_id= {}", self.session_id);
+        self.session_id.clear();
+        self.seq = 0;
+        self.resume_url.clear();
+    }
+
+    /// Clears the current session after the gateway invalidated it.
+    ///
+    /// The gateway asks clients to wait a random amount of time between 1 and 5 seconds before
+    /// identifying again (see [`reconnect_not_before()`]).
+    ///
+    /// <https://discord.com/developers/docs/topics/gateway-events#invalid-session>
+    ///
+    /// [`reconnect_not_before()`]: GatewayContext::reconnect_not_before
+    fn invalidate_session(&mut self) {
+        self.clear_session();
+
+        let delay = INVALID_SESSION_DELAY_MIN
+            + (INVALID_SESSION_DELAY_MAX - INVALID_SESSION_DELAY_MIN).mul_f64(self.rng.next_f64());
+        self.reconnect_not_before = Some(Instant::now() + delay);
+    }
+
+    /// Notifies the context that the socket could not be (re)connected to the gateway.
+    ///
+    /// Schedules the next attempt according to the [`BackoffPolicy`] of the config (see
+    /// [`reconnect_not_before()`]). Returns `false` if the maximum number of attempts is reached,
+    /// the context fails and the application should give up.
+    ///
+    /// # Example
+    /// ```
+    /// # use discord::proto::{BackoffPolicy, Config, GatewayContext};
+    /// # use twilight_model::gateway::Intents;
+    /// let backoff = BackoffPolicy {
+    ///     max_attempts: Some(3),
+    ///     ..Default::default()
+    /// };
+    /// let config = Config::new("TOKEN", Intents::empty()).backoff(backoff);
+    /// let mut conn = GatewayContext::new(config);
+    ///
+    /// assert!(conn.connect_failed());
+    /// assert!(conn.connect_failed());
+    /// assert!(!conn.connect_failed());
+    /// assert!(conn.failed().is_some());
+    /// ```
+    ///
+    /// [`reconnect_not_before()`]: GatewayContext::reconnect_not_before
+    pub fn connect_failed(&mut self) -> bool {
+        let retry = self.schedule_reconnect();
+        log::info!(
+            "connecting failed: attempts= {} reconnect_not_before= {:?}",
+            self.reconnect_attempts,
+            self.reconnect_not_before
+        );
+        if !retry {
+            let code = CloseCode::from(CLOSE_ABNORMAL);
+            self.set_state(State::Failed(code), TransitionCause::ConnectFailed);
+        }
+        retry
+    }
+
+    /// Counts a reconnect attempt and delays it according to the backoff policy
+    ///
+    /// Returns false if the maximum number of attempts is reached.
+    fn schedule_reconnect(&mut self) -> bool {
+        let now = Instant::now();
+        let policy = &self.config.backoff;
+
+        if let Some(connected_at) = self.connected_at.take() {
+            if now.saturating_duration_since(connected_at) >= policy.stable_after {
+                self.reconnect_attempts = 0;
+            }
+        }
+        self.reconnect_attempts = self.reconnect_attempts.saturating_add(1);
+
+        let delay = policy.delay(self.reconnect_attempts, &mut self.rng);
+        if delay > Duration::ZERO {
+            let deadline = now + delay;
+            self.reconnect_not_before = self.reconnect_not_before.max(Some(deadline));
+        }
+
+        !policy.exhausted(self.reconnect_attempts)
+    }
+
+    /// Returns the point in time before which the socket should not be reconnected.
+    ///
+    /// Returns `None` if the socket can be reconnected immediately.
+    /// The deadline is set when the gateway invalidates the session or consecutive reconnects are
+    /// delayed by the [`BackoffPolicy`].
+    ///
+    /// # Example
+    /// ```
+    /// # use discord::proto::GatewayContext;
+    /// # use twilight_model::gateway::{Intents, event::GatewayEvent};
+    /// # use std::time::{Duration, Instant};
+    /// # let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+    /// conn.recv(&GatewayEvent::InvalidateSession(false));
+    /// assert!(conn.should_reconnect());
+    ///
+    /// let deadline = conn.reconnect_not_before().unwrap();
+    /// assert!(deadline >= Instant::now() + Duration::from_millis(500));
+    /// ```
+    pub fn reconnect_not_before(&self) -> Option<Instant> {
+        self.reconnect_not_before
+    }
+
+    fn reset_compression(&mut self) {
+        if let Some(decompressor) = self.decompressor.as_mut() {
+            decompressor.reset();
+        }
+    }
+
+    /// Queue processed events, so that they can be pulled out with [`poll_event()`].
+    ///
+    /// Events are only queued if this was enabled, otherwise the queue would grow forever for
+    /// applications that handle the events returned by [`recv_json()`] or [`recv_binary()`].
+    ///
+    /// [`poll_event()`]: GatewayContext::poll_event
+    /// [`recv_json()`]: GatewayContext::recv_json
+    /// [`recv_binary()`]: GatewayContext::recv_binary
+    pub fn queue_events(&mut self, enabled: bool) {
+        self.events_queued = enabled;
+        if !enabled {
+            self.recv_queue.clear();
+        }
+    }
+
+    fn queue_event(&mut self, event: Event) {
+        if self.events_queued {
+            self.recv_queue.push_back(event);
+        }
+    }
+
+    /// Processes discord events received from the gateway and queues them, so that they can be
+    /// pulled out with [`poll_event()`].
+    ///
+    /// The event is queued before any events generated by the context while processing it. Nothing
+    /// is queued unless the queue was enabled with [`queue_events()`].
+    ///
+    /// # Example
+    /// ```
+    /// # use discord::{proto::GatewayContext, model::gateway::event::{Event, GatewayEvent}};
+    /// # use twilight_model::gateway::Intents;
+    /// # let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+    /// conn.queue_events(true);
+    /// conn.recv_event(GatewayEvent::Hello(41250));
+    ///
+    /// assert_eq!(Some(Event::GatewayHello(41250)), conn.poll_event());
+    /// assert!(matches!(conn.poll_event(), Some(Event::ShardIdentifying(_))));
+    /// assert_eq!(None, conn.poll_event());
+    /// ```
+    ///
+    /// [`poll_event()`]: GatewayContext::poll_event
+    /// [`queue_events()`]: GatewayContext::queue_events
+    pub fn recv_event(&mut self, event: GatewayEvent) {
+        let index = self.recv_queue.len();
+        self.recv(&event);
+        if self.events_queued {
+            self.recv_queue.insert(index, event.into());
+        }
+    }
+
+    /// Returns the next processed event.
+    ///
+    /// Besides the events received with [`recv_event()`], [`recv_json_event()`] or
+    /// [`recv_binary_event()`] the context generates shard events when the state of the
+    /// connection changes, e.g. [`Event::ShardConnected`] once the session is ready or
+    /// [`Event::ShardResuming`] when it starts to resume.
+    ///
+    /// [`recv_event()`]: GatewayContext::recv_event
+    /// [`recv_json_event()`]: GatewayContext::recv_json_event
+    /// [`recv_binary_event()`]: GatewayContext::recv_binary_event
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.recv_queue.pop_front()
     }
 
     /// Processes discord events received from the gateway
+    ///
+    /// If the queue is enabled only shard events generated by the context are queued, use
+    /// [`recv_event()`] to queue the received event as well.
+    ///
+    /// [`recv_event()`]: GatewayContext::recv_event
     pub fn recv(&mut self, event: &GatewayEvent) {
         log::trace!("gateway event= {:?}", event);
 
@@ -239,31 +776,42 @@ impl GatewayContext {
         match event {
             // an invalid session can potentially be resumed
             GatewayEvent::InvalidateSession(resumable) => {
-                self.state = if *resumable {
+                let state = if *resumable {
                     State::Resume
                 } else {
+                    self.invalidate_session();
                     State::Reconnect
                 };
+                self.set_state(state, TransitionCause::InvalidSession(*resumable));
             }
             // a reconnect event can be resumed after the socket has reconnected to the gateway
             GatewayEvent::Reconnect => {
-                self.state = State::Resume;
+                self.set_state(self.resume_state(), TransitionCause::ReconnectRequested);
             }
             // queue a heartbeat if it was requested
             GatewayEvent::Heartbeat(_) => {
-                self.queue_heartbeat();
+                self.push_heartbeat();
             }
-            // do nothing if hearbeat was ack'd
+            // remember that the last heartbeat was ack'd
             GatewayEvent::HeartbeatAck => {
-                // TODO: maybe keep track if the heartbeat was ack'd and if not send it again
+                self.heartbeat_acked = true;
+                self.stats.record_ack(Instant::now());
             }
             // hello events indicate that the underlying socket has (re)connected to the gateway
             GatewayEvent::Hello(heartbeat_interval) => {
                 log::debug!("recv hello: heartbeat_interval= {}", heartbeat_interval);
 
                 self.heartbeat_interval = *heartbeat_interval;
+                self.stats.record_connect();
+                // no heartbeat has been sent over the new connection yet
+                self.heartbeat_acked = true;
+                self.first_heartbeat_delay =
+                    Duration::from_millis(*heartbeat_interval).mul_f64(self.rng.next_f64());
+                self.next_heartbeat = Some(Instant::now() + self.first_heartbeat_delay);
+                // the rate limit applies per connection
+                self.ratelimiter = RateLimiter::new(Duration::from_millis(*heartbeat_interval));
 
-                self.state = match self.state {
+                let state = match self.state {
                     // if the connection was ready we try to resume first
                     State::Resume | State::Ready => {
                         self.send_queue
@@ -276,19 +824,16 @@ impl GatewayContext {
                     }
                     // client got reconnected
                     _ => {
-                        self.send_queue
-                            .push_back(GatewayCommand::Identify(Identify::new(IdentifyInfo {
-                                compress: false,
-                                token: self.config.token.clone(),
-                                shard: Some(self.config.shard),
-                                intents: self.config.intents,
-                                large_threshold: self.config.large_threshold,
-                                presence: self.config.presence.clone(),
-                                properties: self.config.identify_properties.clone(),
-                            })));
+                        if self.identify_gated {
+                            log::debug!("holding back identify until it is allowed");
+                            self.identify_pending = true;
+                        } else {
+                            self.push_identify();
+                        }
                         State::Identify
                     }
-                }
+                };
+                self.set_state(state, TransitionCause::Hello);
             }
             GatewayEvent::Dispatch(seq, event) => {
                 match event {
@@ -305,18 +850,115 @@ impl GatewayContext {
 
                         self.resume_url = ready.resume_gateway_url.clone();
                         self.session_id = ready.session_id.clone();
-                        self.state = State::Ready;
+                        self.set_state(State::Ready, TransitionCause::Ready);
                     }
                     DispatchEvent::Resumed => {
                         log::info!("resumed: session_id= {}", self.session_id);
-                        self.state = State::Ready;
+                        self.set_state(State::Ready, TransitionCause::Resumed);
                     }
                     _ => {}
                 }
                 log::debug!("recv dispatch: kind= {:?} seq= {}", event.kind(), seq);
 
-                self.seq = *seq;
-                // self.recv_queue.push_back((*event).into "
+                self.stats.dispatches += 1;
+
+                if let DispatchEvent::Ready(_) = event {
+                    // a new session starts a new sequence
+                    self.seq = *seq;
+                } else {
+                    self.check_seq(*seq);
+                }
+            }
+        }
+    }
+
+    /// Updates the sequence number and records gaps and regressions in the received sequence
+    ///
+    /// The sequence number never moves backwards, so that a resume does not replay events twice.
+    /// Replayed events are not checked, since they may repeat events that were already received.
+    fn check_seq(&mut self, seq: u64) {
+        if self.state == State::Replaying {
+            self.seq = self.seq.max(seq);
+            return;
+        }
+
+        let expected = self.seq.saturating_add(1);
+        if seq > expected {
+            let missed = seq - expected;
+            log::warn!(
+                "sequence gap: expected= {} received= {} missed= {} state= {:?}",
+                expected,
+                seq,
+                missed,
+                self.state
+            );
+            self.stats.sequence_gaps += 1;
+            self.stats.missed_events += missed;
+        } else if seq < expected {
+            log::warn!(
+                "sequence regression: expected= {} received= {} state= {:?}",
+                expected,
+                seq,
+                self.state
+            );
+            self.stats.sequence_regressions += 1;
+            return;
+        }
+
+        self.seq = seq;
+    }
+
+    /// Returns the point in time at which [`on_timeout()`] should be called next.
+    ///
+    /// This includes the point in time at which commands that are held back by the rate limiter
+    /// can be sent (see [`next_send()`]).
+    ///
+    /// While the connection has to be reconnected, the point in time at which the backoff expires
+    /// is returned instead (see [`reconnect_not_before()`]), or `None` if the socket can be
+    /// reconnected immediately. Returns `None` as well once the connection failed.
+    ///
+    /// [`on_timeout()`]: GatewayContext::on_timeout
+    /// [`next_send()`]: GatewayContext::next_send
+    /// [`reconnect_not_before()`]: GatewayContext::reconnect_not_before
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        if self.failed().is_some() {
+            return None;
+        }
+        if self.should_reconnect() {
+            return self.reconnect_not_before;
+        }
+
+        self.next_heartbeat
+            .into_iter()
+            .chain(self.next_send())
+            .min()
+    }
+
+    /// Advances the timers of the context to `now`.
+    ///
+    /// Queues a heartbeat if one is due. If the previous heartbeat has not been acknowledged until
+    /// then, the connection is marked for reconnecting instead (see [`queue_heartbeat()`]).
+    ///
+    /// # Example
+    /// ```
+    /// # use discord::proto::{GatewayContext, GatewayCommand};
+    /// # use twilight_model::gateway::{Intents, event::GatewayEvent};
+    /// # let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+    /// conn.recv(&GatewayEvent::Hello(41250));
+    /// # let _identify = conn.send();
+    ///
+    /// let deadline = conn.poll_timeout().unwrap();
+    /// conn.on_timeout(deadline);
+    /// assert!(matches!(conn.send(), Some(GatewayCommand::Heartbeat(_))));
+    /// ```
+    ///
+    /// [`queue_heartbeat()`]: GatewayContext::queue_heartbeat
+    pub fn on_timeout(&mut self, now: Instant) {
+        if let Some(deadline) = self.next_heartbeat {
+            if deadline <= now {
+                self.next_heartbeat = Some(now + Duration::from_millis(self.heartbeat_interval));
+                // clears the deadline if the connection turned out to be a zombie
+                self.queue_heartbeat();
             }
         }
     }
@@ -326,19 +968,72 @@ impl GatewayContext {
     /// Takes an JSON string as input and returns the deserialized [`GatewayEvent`].
     #[cfg(feature = "json")]
     pub fn recv_json(&mut self, input: &str) -> Result<GatewayEvent, serde_json::Error> {
-        use serde::de::DeserializeSeed;
-        use serde_json::Deserializer;
-        use twilight_model::gateway::event::GatewayEventDeserializer;
-
-        let deserializer = GatewayEventDeserializer::from_json(input).unwrap();
-        let mut json_deserializer = Deserializer::from_str(input);
-        let event = deserializer.deserialize(&mut json_deserializer)?;
+        let event = deserialize_json(input)?;
         self.recv(&event);
         Ok(event)
     }
 
+    /// Processes a binary message received from the gateway.
+    ///
+    /// If transport compression is enabled the message is decompressed first. Messages can be
+    /// split over multiple frames, so `None` is returned if the message is not yet complete.
+    ///
+    /// Takes data in the configured [`Encoding`] as input and returns the deserialized
+    /// [`GatewayEvent`].
+    #[cfg(feature = "json")]
+    pub fn recv_binary(&mut self, frame: &[u8]) -> Result<Option<GatewayEvent>, crate::Error> {
+        let event = self.decode_binary(frame)?;
+        if let Some(event) = &event {
+            self.recv(event);
+        }
+        Ok(event)
+    }
+
+    /// Processes a discord event received from the gateway and queues it like [`recv_event()`].
+    ///
+    /// Takes an JSON string as input.
+    ///
+    /// [`recv_event()`]: GatewayContext::recv_event
+    #[cfg(feature = "json")]
+    pub fn recv_json_event(&mut self, input: &str) -> Result<(), serde_json::Error> {
+        let event = deserialize_json(input)?;
+        self.recv_event(event);
+        Ok(())
+    }
+
+    /// Processes a binary message received from the gateway and queues it like [`recv_event()`].
+    ///
+    /// Nothing is queued until a message that is split over multiple frames is complete, see
+    /// [`recv_binary()`].
+    ///
+    /// [`recv_event()`]: GatewayContext::recv_event
+    /// [`recv_binary()`]: GatewayContext::recv_binary
+    #[cfg(feature = "json")]
+    pub fn recv_binary_event(&mut self, frame: &[u8]) -> Result<(), crate::Error> {
+        if let Some(event) = self.decode_binary(frame)? {
+            self.recv_event(event);
+        }
+        Ok(())
+    }
+
+    /// Decompresses and deserializes a binary message, returns `None` if it is not yet complete
+    #[cfg(feature = "json")]
+    fn decode_binary(&mut self, frame: &[u8]) -> Result<Option<GatewayEvent>, crate::Error> {
+        let encoding = self.config.encoding;
+        let event = match self.decompressor.as_mut() {
+            Some(decompressor) => match decompressor.decompress(frame)? {
+                Some(msg) => deserialize(encoding, msg)?,
+                None => return Ok(None),
+            },
+            None => deserialize(encoding, frame)?,
+        };
+        Ok(Some(event))
+    }
+
     /// Create an iterator of all the commands to be sent to the gateway
     ///
+    /// Commands that are held back by the rate limiter are not yielded (see [`send()`]).
+    ///
     /// # Example
     /// ```no_run
     /// # use discord::proto::{GatewayContext, GatewayCommand};
@@ -349,9 +1044,11 @@ impl GatewayContext {
     ///     send_to_socket(cmd);
     /// }
     /// ```
+    ///
+    /// [`send()`]: GatewayContext::send
     pub fn send_iter(&mut self) -> impl Iterator<Item = GatewayCommand> + '_ {
         log::trace!("sending commands {:?}", self.send_queue);
-        self.send_queue.drain(..)
+        std::iter::from_fn(move || self.send())
     }
 
     /// Create an iterator of all the commands to be sent to the gateway
@@ -363,9 +1060,24 @@ impl GatewayContext {
             .map(|cmd| serde_json::to_string(&cmd).expect("command is always serializable"))
     }
 
+    /// Create an iterator of all the commands to be sent to the gateway
+    ///
+    /// The commands will already be encoded in ETF.
+    #[cfg(feature = "etf")]
+    pub fn send_iter_etf(&mut self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        self.send_iter().map(|cmd| encode_etf(&cmd))
+    }
+
     /// Creates a discord command to be sent to the gateway.
     ///
-    /// Returns `None` if there is nothing to send.
+    /// Returns `None` if there is nothing to send or the gateway rate limit does not allow sending
+    /// another command at the moment. [`next_send()`] returns the point in time at which the
+    /// held back commands can be sent.
+    ///
+    /// Other commands than heartbeat, identify and resume are held back until the session was
+    /// identified or resumed, since the gateway closes connections that send them earlier.
+    /// Heartbeat, identify and resume commands skip held back commands and are allowed to use a
+    /// reserved part of the rate limit.
     ///
     /// # Example
     /// ```
@@ -377,31 +1089,99 @@ impl GatewayContext {
     ///     send_to_socket(cmd);
     /// }
     /// ```
+    ///
+    /// [`next_send()`]: GatewayContext::next_send
     pub fn send(&mut self) -> Option<GatewayCommand> {
-        let cmd = self.send_queue.pop_front();
+        self.send_at(Instant::now())
+    }
+
+    fn send_at(&mut self, now: Instant) -> Option<GatewayCommand> {
+        let first = if self.accepts_commands() {
+            0
+        } else {
+            self.send_queue
+                .iter()
+                .position(GatewayCommand::is_priority)?
+        };
+        let priority = self.send_queue.get(first)?.is_priority();
+
+        let index = if self.ratelimiter.acquire(now, priority) {
+            first
+        } else {
+            // priority commands skip the held back commands and use the reserved budget
+            match self.send_queue.iter().position(GatewayCommand::is_priority) {
+                Some(index) if !priority && self.ratelimiter.acquire(now, true) => index,
+                _ => {
+                    log::debug!("rate limited: {} commands held back", self.send_queue.len());
+                    return None;
+                }
+            }
+        };
+
+        let cmd = self.send_queue.remove(index)?;
         log::trace!("sending command: {:?}", cmd);
-        cmd
+        self.stats.record_sent(&cmd, now);
+        Some(cmd)
+    }
+
+    /// Returns true if the gateway accepts other commands than heartbeat, identify and resume
+    fn accepts_commands(&self) -> bool {
+        let session_pending = self
+            .send_queue
+            .iter()
+            .any(|cmd| matches!(cmd, GatewayCommand::Identify(_) | GatewayCommand::Resume(_)));
+        matches!(self.state, State::Ready | State::Replaying) && !session_pending
     }
 
     /// Creates a single discord command to be sent to the gateway.
     ///
-    /// The command will already be serialized as JSON.
+    /// The command will already be encoded in ETF.
     ///
     /// # Example
     /// see [send]
     ///
     /// [send]: GatewayContext::send
-    #[cfg(feature = "json")]
-    pub fn send_json(&mut self) -> Option<String> {
-        self.send()
-            .map(|cmd| serde_json::to_string(&cmd).expect("command is always serializable"))
+    #[cfg(feature = "etf")]
+    pub fn send_etf(&mut self) -> Option<Vec<u8>> {
+        self.send().map(|cmd| encode_etf(&cmd))
     }
 
-    /// Returns true if the underlying gateway connection has to be reconnected
-    pub fn should_reconnect(&self) -> bool {
-        match self.state {
-            State::Resume | State::Reconnect => true,
-            State::Failed(_) => false,
+    /// Returns the point in time at which the next queued command can be sent.
+    ///
+    /// Returns `None` if there are no commands queued.
+    pub fn next_send(&self) -> Option<Instant> {
+        let priority = self.send_queue.iter().any(GatewayCommand::is_priority);
+        if !priority && (self.send_queue.is_empty() || !self.accepts_commands()) {
+            return None;
+        }
+
+        // a past instant means that the command can be sent right away
+        Some(
+            self.ratelimiter
+                .next_available(priority)
+                .unwrap_or_else(Instant::now),
+        )
+    }
+
+    /// Creates a single discord command to be sent to the gateway.
+    ///
+    /// The command will already be serialized as JSON.
+    ///
+    /// # Example
+    /// see [send]
+    ///
+    /// [send]: GatewayContext::send
+    #[cfg(feature = "json")]
+    pub fn send_json(&mut self) -> Option<String> {
+        self.send()
+            .map(|cmd| serde_json::to_string(&cmd).expect("command is always serializable"))
+    }
+
+    /// Returns true if the underlying gateway connection has to be reconnected
+    pub fn should_reconnect(&self) -> bool {
+        match self.state {
+            State::Resume | State::Reconnect => true,
+            State::Failed(_) => false,
             _ => self.socket_closed,
         }
     }
@@ -411,6 +1191,108 @@ impl GatewayContext {
         &self.state
     }
 
+    fn set_state(&mut self, mut state: State, cause: TransitionCause) {
+        if self.state == state {
+            return;
+        }
+
+        if let State::Resume | State::Reconnect = state {
+            if !self.schedule_reconnect() {
+                log::warn!(
+                    "giving up after {} reconnect attempts",
+                    self.reconnect_attempts
+                );
+                state = match cause {
+                    TransitionCause::Closed(code) => State::Failed(code),
+                    _ => State::Failed(CloseCode::from(CLOSE_ABNORMAL)),
+                };
+            }
+        }
+
+        log::debug!(
+            "state transition: {:?} -> {:?} ({:?})",
+            self.state,
+            state,
+            cause
+        );
+        if let Some(event) = self.shard_event(&state) {
+            self.queue_event(event);
+        }
+        if state != State::Identify {
+            // the held back identify belongs to the previous connection
+            self.identify_pending = false;
+        }
+
+        let from = std::mem::replace(&mut self.state, state.clone());
+        if self.transitions.len() >= TRANSITION_QUEUE_SIZE {
+            self.transitions.pop_front();
+        }
+        self.transitions.push_back(Transition {
+            from,
+            to: state,
+            cause,
+        });
+    }
+
+    /// Synthetic shard event that is queued when the context changes into the state
+    fn shard_event(&self, state: &State) -> Option<Event> {
+        let (shard_id, shard_total) = (self.config.shard.index(), self.config.shard.total());
+        let event = match state {
+            State::Identify => Event::ShardIdentifying(Identifying {
+                shard_id,
+                shard_total,
+            }),
+            State::Replaying => Event::ShardResuming(Resuming {
+                seq: self.seq,
+                shard_id,
+            }),
+            State::Ready => Event::ShardConnected(Connected {
+                heartbeat_interval: self.heartbeat_interval,
+                shard_id,
+            }),
+            State::Resume | State::Reconnect => Event::ShardReconnecting(Reconnecting { shard_id }),
+            State::Closed | State::Failed(_) => return None,
+        };
+        Some(event)
+    }
+
+    /// Returns the next state transition that happened while processing received events.
+    ///
+    /// Transitions are queued until they are polled, so applications can react to them, e.g. to
+    /// log that the session is ready or that a resume finished. Only the last 16 transitions are
+    /// kept if they are never polled.
+    ///
+    /// # Example
+    /// ```
+    /// # use discord::proto::{GatewayContext, State, TransitionCause};
+    /// # use twilight_model::gateway::{Intents, event::GatewayEvent};
+    /// # let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+    /// conn.recv(&GatewayEvent::Hello(41250));
+    ///
+    /// while let Some(transition) = conn.poll_transition() {
+    ///     assert_eq!(State::Closed, transition.from);
+    ///     assert_eq!(State::Identify, transition.to);
+    ///     assert_eq!(TransitionCause::Hello, transition.cause);
+    /// }
+    /// ```
+    pub fn poll_transition(&mut self) -> Option<Transition> {
+        self.transitions.pop_front()
+    }
+
+    /// Returns statistics about the connection, e.g. the heartbeat latency
+    ///
+    /// # Example
+    /// ```
+    /// # use discord::{proto::GatewayContext, model::gateway::Intents};
+    /// # let ctx = GatewayContext::new(("TOKEN", Intents::empty()));
+    /// if let Some(latency) = ctx.stats().latency() {
+    ///     println!("pong: {}ms", latency.as_millis());
+    /// }
+    /// ```
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
     /// Returns true if the connection is closed
     pub fn closed(&self) -> bool {
         matches!(self.state, State::Closed | State::Reconnect | State::Resume)
@@ -425,6 +1307,35 @@ impl GatewayContext {
     }
 }
 
+/// Deserializes a JSON encoded gateway event
+#[cfg(feature = "json")]
+fn deserialize_json(input: &str) -> Result<GatewayEvent, serde_json::Error> {
+    use serde::de::{DeserializeSeed, Error};
+    use serde_json::Deserializer;
+    use twilight_model::gateway::event::GatewayEventDeserializer;
+
+    let deserializer = GatewayEventDeserializer::from_json(input)
+        .ok_or_else(|| serde_json::Error::custom("missing opcode"))?;
+    let mut json_deserializer = Deserializer::from_str(input);
+    deserializer.deserialize(&mut json_deserializer)
+}
+
+/// Deserializes a gateway event in the given encoding
+#[cfg(feature = "json")]
+fn deserialize(encoding: Encoding, input: &[u8]) -> Result<GatewayEvent, crate::Error> {
+    match encoding {
+        Encoding::Json => Ok(deserialize_json(std::str::from_utf8(input)?)?),
+        #[cfg(feature = "etf")]
+        Encoding::Etf => etf::deserialize_event(input),
+    }
+}
+
+#[cfg(feature = "etf")]
+fn encode_etf(cmd: &GatewayCommand) -> Vec<u8> {
+    let value = serde_json::to_value(cmd).expect("command is always serializable");
+    etf::encode(&value)
+}
+
 #[cfg(test)]
 mod tests {
     use twilight_model::{
@@ -545,12 +1456,121 @@ mod tests {
         conn.recv(&GatewayEvent::InvalidateSession(false));
         assert_eq!(State::Reconnect, *conn.state());
         assert!(conn.should_reconnect());
+        assert!(conn.reconnect_not_before().is_some());
 
+        // there is no session to resume
         conn.recv(&GatewayEvent::Reconnect);
-        assert_eq!(State::Resume, *conn.state());
+        assert_eq!(State::Reconnect, *conn.state());
         assert!(conn.should_reconnect());
     }
 
+    #[test]
+    fn invalid_session_reidentify() {
+        let config = Config::new("TOKEN", Intents::empty()).rng_seed(7);
+        let mut conn = GatewayContext::new(config);
+        conn.recv(&GatewayEvent::Hello(10));
+        let _identify = conn.send().unwrap();
+        conn.recv(&create_default_ready());
+        conn.recv(&GatewayEvent::Dispatch(1, DispatchEvent::PresencesReplace));
+        assert!(conn.session().is_some());
+        assert_eq!(None, conn.reconnect_not_before());
+
+        let now = Instant::now();
+        conn.recv(&GatewayEvent::InvalidateSession(false));
+        assert_eq!(None, conn.session());
+        assert_eq!(0, conn.seq);
+        assert_eq!("", conn.resume_gateway_url());
+
+        let deadline = conn.reconnect_not_before().unwrap();
+        assert!(deadline >= now + INVALID_SESSION_DELAY_MIN);
+        assert!(deadline <= Instant::now() + INVALID_SESSION_DELAY_MAX);
+
+        // the new connection identifies with a new session
+        conn.socket_connected();
+        assert_eq!(None, conn.reconnect_not_before());
+        conn.recv(&GatewayEvent::Hello(10));
+        assert!(matches!(conn.send(), Some(GatewayCommand::Identify(_))));
+    }
+
+    #[test]
+    fn reconnect_backoff() {
+        let backoff = BackoffPolicy {
+            jitter: false,
+            max_attempts: Some(4),
+            ..Default::default()
+        };
+        let config = Config::new("TOKEN", Intents::empty()).backoff(backoff);
+        let mut conn = GatewayContext::new(config);
+        conn.socket_connected();
+        conn.recv(&GatewayEvent::Hello(10));
+        let _identify = conn.send().unwrap();
+        conn.recv(&create_default_ready());
+
+        // the first reconnect is not delayed
+        conn.recv_close_code(1000u16);
+        assert_eq!(1, conn.reconnect_attempts);
+        assert_eq!(None, conn.reconnect_not_before());
+
+        // reconnecting fails
+        let now = Instant::now();
+        assert!(conn.connect_failed());
+        let deadline = conn.reconnect_not_before().unwrap();
+        assert!(deadline >= now + Duration::from_secs(1));
+        assert!(deadline <= Instant::now() + Duration::from_secs(1));
+
+        // the connection is closed again right after connecting
+        conn.socket_connected();
+        conn.recv(&GatewayEvent::Hello(10));
+        conn.recv(&GatewayEvent::Dispatch(1, DispatchEvent::Resumed));
+        conn.recv_close_code(1000u16);
+        assert_eq!(3, conn.reconnect_attempts);
+        assert!(conn.reconnect_not_before().unwrap() >= now + Duration::from_secs(2));
+
+        // a stable connection resets the counter
+        conn.config.backoff.stable_after = Duration::ZERO;
+        conn.socket_connected();
+        conn.recv(&GatewayEvent::Hello(10));
+        conn.recv(&GatewayEvent::Dispatch(1, DispatchEvent::Resumed));
+        conn.recv(&GatewayEvent::Reconnect);
+        assert_eq!(1, conn.reconnect_attempts);
+        assert_eq!(None, conn.reconnect_not_before());
+
+        // the context gives up once reconnecting failed too often
+        conn.config.backoff.stable_after = Duration::from_secs(60);
+        assert!(conn.connect_failed());
+        assert!(conn.connect_failed());
+        assert!(!conn.connect_failed());
+        assert!(!conn.should_reconnect());
+        assert_eq!(Some(CloseCode::Other(1006)), conn.failed());
+    }
+
+    #[test]
+    fn reconnect_attempts_exhausted() {
+        let backoff = BackoffPolicy {
+            max_attempts: Some(3),
+            ..Default::default()
+        };
+        let config = Config::new("TOKEN", Intents::empty()).backoff(backoff);
+        let mut conn = GatewayContext::new(config);
+        conn.recv(&GatewayEvent::Hello(10));
+        let _identify = conn.send().unwrap();
+        conn.recv(&create_default_ready());
+
+        // connections that are closed right away count towards the limit
+        conn.socket_connected();
+        conn.recv_close_code(1006u16);
+        conn.socket_connected();
+        conn.recv(&GatewayEvent::Hello(10));
+        conn.recv(&GatewayEvent::Reconnect);
+        assert!(conn.should_reconnect());
+
+        conn.socket_connected();
+        conn.recv(&GatewayEvent::Hello(10));
+        conn.recv_close_code(1006u16);
+        assert!(!conn.should_reconnect());
+        assert_eq!(Some(CloseCode::Other(1006)), conn.failed());
+    }
+
     #[test]
     fn heartbeat_request() {
         let token = "TOKEN";
@@ -564,4 +1584,625 @@ mod tests {
             conn.send()
         );
     }
+
+    #[test]
+    fn heartbeat_ack() {
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+        conn.recv(&GatewayEvent::Hello(10));
+        let _identify = conn.send().unwrap();
+        conn.recv(&create_default_ready());
+
+        conn.queue_heartbeat();
+        assert!(!conn.heartbeat_acked);
+        assert!(matches!(conn.send(), Some(GatewayCommand::Heartbeat(_))));
+
+        conn.recv(&GatewayEvent::HeartbeatAck);
+        assert!(conn.heartbeat_acked);
+
+        conn.queue_heartbeat();
+        assert!(matches!(conn.send(), Some(GatewayCommand::Heartbeat(_))));
+        assert_eq!(State::Ready, *conn.state());
+        assert!(!conn.should_reconnect());
+    }
+
+    #[test]
+    fn zombie_connection() {
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+        conn.recv(&GatewayEvent::Hello(10));
+        let _identify = conn.send().unwrap();
+        conn.recv(&create_default_ready());
+
+        conn.queue_heartbeat();
+        let _heartbeat = conn.send().unwrap();
+
+        // the previous heartbeat was never ack'd
+        conn.queue_heartbeat();
+        assert_eq!(None, conn.send());
+        assert_eq!(State::Resume, *conn.state());
+        assert!(conn.should_reconnect());
+
+        // the new connection starts without an outstanding heartbeat
+        conn.recv(&GatewayEvent::Hello(10));
+        assert!(conn.heartbeat_acked);
+        assert_eq!(State::Replaying, *conn.state());
+    }
+
+    #[test]
+    fn heartbeat_timeout() {
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+        assert_eq!(None, conn.poll_timeout());
+
+        conn.recv(&GatewayEvent::Hello(10));
+        let _identify = conn.send().unwrap();
+        conn.recv(&create_default_ready());
+
+        // nothing happens before the deadline
+        let deadline = conn.poll_timeout().unwrap();
+        conn.on_timeout(deadline - Duration::from_millis(1));
+        assert_eq!(None, conn.send());
+
+        conn.on_timeout(deadline);
+        assert!(matches!(conn.send(), Some(GatewayCommand::Heartbeat(_))));
+        let next = conn.poll_timeout().unwrap();
+        assert_eq!(deadline + Duration::from_millis(10), next);
+
+        conn.recv(&GatewayEvent::HeartbeatAck);
+        conn.on_timeout(next);
+        assert!(matches!(conn.send(), Some(GatewayCommand::Heartbeat(_))));
+
+        // the ack is missing when the next heartbeat is due
+        let next = conn.poll_timeout().unwrap();
+        conn.on_timeout(next);
+        assert_eq!(None, conn.send());
+        assert!(conn.should_reconnect());
+        assert_eq!(None, conn.poll_timeout());
+    }
+
+    #[test]
+    fn first_heartbeat_jitter() {
+        let config = Config::new("TOKEN", Intents::empty()).rng_seed(7);
+        let mut a = GatewayContext::new(config.clone());
+        let mut b = GatewayContext::new(config);
+
+        let before = Instant::now();
+        a.recv(&GatewayEvent::Hello(41250));
+        b.recv(&GatewayEvent::Hello(41250));
+
+        let delay = a.first_heartbeat_delay();
+        assert_eq!(delay, b.first_heartbeat_delay());
+        assert!(delay < Duration::from_millis(41250));
+        assert!(a.next_heartbeat.unwrap() >= before + delay);
+
+        // every new connection is jittered again
+        a.recv(&GatewayEvent::Hello(41250));
+        assert_ne!(delay, a.first_heartbeat_delay());
+    }
+
+    #[test]
+    fn ratelimit_commands() {
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+        conn.recv(&GatewayEvent::Hello(41250));
+        let _identify = conn.send().unwrap();
+        conn.recv(&create_default_ready());
+
+        for _ in 0..120 {
+            conn.enqueue_command(GatewayCommand::RequestGulidMembers(
+                RequestGuildMembers::builder(Id::new(1)).query("", None),
+            ));
+        }
+
+        // the identify and the reserved heartbeats are subtracted from the budget
+        let sent = conn.send_iter().count();
+        assert_eq!(116, sent);
+        assert_eq!(4, conn.send_queue.len());
+        assert!(conn.next_send().unwrap() > Instant::now());
+        assert!(conn.poll_timeout().unwrap() <= conn.next_send().unwrap());
+
+        // heartbeats skip the held back commands
+        conn.queue_heartbeat();
+        assert!(matches!(conn.send(), Some(GatewayCommand::Heartbeat(_))));
+        assert_eq!(None, conn.send());
+    }
+
+    #[test]
+    fn command_order() {
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+        conn.recv(&GatewayEvent::Hello(41250));
+        let _identify = conn.send().unwrap();
+        conn.recv(&create_default_ready());
+
+        // commands keep their order as long as they are not rate limited
+        conn.enqueue_command(GatewayCommand::RequestGulidMembers(
+            RequestGuildMembers::builder(Id::new(1)).query("", None),
+        ));
+        conn.queue_heartbeat();
+        assert!(matches!(
+            conn.send(),
+            Some(GatewayCommand::RequestGulidMembers(_))
+        ));
+        assert!(matches!(conn.send(), Some(GatewayCommand::Heartbeat(_))));
+    }
+
+    #[test]
+    fn commands_held_until_ready() {
+        use twilight_model::gateway::presence::{ActivityType, MinimalActivity, Status};
+
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+        conn.gate_identify(true);
+        conn.recv(&GatewayEvent::Hello(41250));
+
+        let activity = MinimalActivity {
+            kind: ActivityType::Playing,
+            name: "test".to_string(),
+            url: None,
+        };
+        let presence = UpdatePresence::new(vec![activity.into()], false, None, Status::Online);
+        conn.enqueue_command(GatewayCommand::UpdatePresence(presence.unwrap()));
+
+        // the presence update must not be sent before identifying
+        assert_eq!(None, conn.send());
+        assert_eq!(None, conn.next_send());
+
+        conn.allow_identify();
+        assert!(matches!(conn.send(), Some(GatewayCommand::Identify(_))));
+        assert_eq!(None, conn.send());
+
+        conn.recv(&create_default_ready());
+        assert!(matches!(
+            conn.send(),
+            Some(GatewayCommand::UpdatePresence(_))
+        ));
+
+        // held back again until the session is resumed after reconnecting
+        conn.enqueue_command(GatewayCommand::RequestGulidMembers(
+            RequestGuildMembers::builder(Id::new(1)).query("", None),
+        ));
+        conn.recv(&GatewayEvent::Reconnect);
+        assert_eq!(None, conn.send());
+        conn.recv(&GatewayEvent::Hello(41250));
+        assert!(matches!(conn.send(), Some(GatewayCommand::Resume(_))));
+        assert!(matches!(
+            conn.send(),
+            Some(GatewayCommand::RequestGulidMembers(_))
+        ));
+    }
+
+    #[test]
+    fn stats() {
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+        conn.recv(&GatewayEvent::Hello(10));
+        let _identify = conn.send().unwrap();
+        conn.recv(&create_default_ready());
+
+        conn.queue_heartbeat();
+        let _heartbeat = conn.send().unwrap();
+        conn.recv(&GatewayEvent::HeartbeatAck);
+
+        // reconnect and resume
+        conn.recv(&GatewayEvent::Hello(10));
+        let _resume = conn.send().unwrap();
+        conn.recv(&GatewayEvent::Dispatch(1, DispatchEvent::Resumed));
+
+        let stats = conn.stats();
+        assert_eq!(1, stats.heartbeats_sent);
+        assert_eq!(1, stats.heartbeat_acks);
+        assert!(stats.latency().is_some());
+        assert_eq!(2, stats.connections);
+        assert_eq!(1, stats.reconnects);
+        assert_eq!(1, stats.identifies);
+        assert_eq!(1, stats.resumes);
+        assert_eq!(2, stats.dispatches);
+    }
+
+    #[test]
+    fn sequence_gaps() {
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+        conn.recv(&GatewayEvent::Hello(10));
+        conn.recv(&create_default_ready());
+        conn.recv(&GatewayEvent::Dispatch(1, DispatchEvent::PresencesReplace));
+        assert_eq!(0, conn.stats().sequence_gaps);
+
+        // events 2 to 4 were lost
+        conn.recv(&GatewayEvent::Dispatch(5, DispatchEvent::PresencesReplace));
+        assert_eq!(5, conn.seq);
+        assert_eq!(1, conn.stats().sequence_gaps);
+        assert_eq!(3, conn.stats().missed_events);
+
+        // the sequence number does not move backwards
+        conn.recv(&GatewayEvent::Dispatch(4, DispatchEvent::PresencesReplace));
+        assert_eq!(5, conn.seq);
+        assert_eq!(1, conn.stats().sequence_regressions);
+
+        // replayed events after resuming continue the sequence
+        conn.recv(&GatewayEvent::Reconnect);
+        conn.recv(&GatewayEvent::Hello(10));
+        conn.recv(&GatewayEvent::Dispatch(6, DispatchEvent::PresencesReplace));
+        conn.recv(&GatewayEvent::Dispatch(7, DispatchEvent::Resumed));
+        assert_eq!(7, conn.seq);
+        assert_eq!(1, conn.stats().sequence_gaps);
+
+        // replayed events are neither gaps nor regressions
+        conn.recv(&GatewayEvent::Reconnect);
+        conn.recv(&GatewayEvent::Hello(10));
+        conn.recv(&GatewayEvent::Dispatch(5, DispatchEvent::PresencesReplace));
+        conn.recv(&GatewayEvent::Dispatch(9, DispatchEvent::PresencesReplace));
+        assert_eq!(9, conn.seq);
+        conn.recv(&GatewayEvent::Dispatch(10, DispatchEvent::Resumed));
+        assert_eq!(1, conn.stats().sequence_gaps);
+        assert_eq!(1, conn.stats().sequence_regressions);
+
+        // the sequence number can not overflow
+        conn.seq = u64::MAX;
+        conn.recv(&GatewayEvent::Dispatch(
+            u64::MAX,
+            DispatchEvent::PresencesReplace,
+        ));
+        assert_eq!(u64::MAX, conn.seq);
+
+        // a new session starts a new sequence
+        conn.recv(&create_default_ready());
+        assert_eq!(0, conn.seq);
+        assert_eq!(1, conn.stats().sequence_regressions);
+    }
+
+    #[test]
+    #[cfg(all(feature = "json", feature = "zlib"))]
+    fn recv_compressed() {
+        use flate2::{Compress, FlushCompress};
+
+        let config = Config::new("TOKEN", Intents::empty()).compression(Compression::ZlibStream);
+        assert!(config
+            .connection_url("wss://gateway.discord.gg")
+            .ends_with("&compress=zlib-stream"));
+
+        let mut conn = GatewayContext::new(config);
+        let mut compress = Compress::new(flate2::Compression::default(), true);
+        let mut deflate = |msg: &str| {
+            let mut output = Vec::with_capacity(128);
+            compress
+                .compress_vec(msg.as_bytes(), &mut output, FlushCompress::Sync)
+                .unwrap();
+            output
+        };
+
+        let hello = deflate(r#"{"op":10,"d":{"heartbeat_interval":41250}}"#);
+        let (first, second) = hello.split_at(5);
+        assert!(conn.recv_binary(first).unwrap().is_none());
+        assert!(matches!(
+            conn.recv_binary(second).unwrap(),
+            Some(GatewayEvent::Hello(41250))
+        ));
+        assert_eq!(State::Identify, *conn.state());
+
+        let ack = deflate(r#"{"op":11,"d":null}"#);
+        assert!(matches!(
+            conn.recv_binary(&ack).unwrap(),
+            Some(GatewayEvent::HeartbeatAck)
+        ));
+
+        // the stream of a new connection starts with a new zlib header
+        conn.socket_connected();
+        let mut compress = Compress::new(flate2::Compression::default(), true);
+        let mut output = Vec::with_capacity(128);
+        compress
+            .compress_vec(br#"{"op":11,"d":null}"#, &mut output, FlushCompress::Sync)
+            .unwrap();
+        conn.queue_events(true);
+        conn.recv_binary_event(&output).unwrap();
+        assert_eq!(Some(Event::GatewayHeartbeatAck), conn.poll_event());
+    }
+
+    #[test]
+    #[cfg(feature = "etf")]
+    fn recv_etf() {
+        let config = Config::new("TOKEN", Intents::empty()).encoding(Encoding::Etf);
+        assert!(config
+            .connection_url("wss://gateway.discord.gg")
+            .ends_with("?v=10&encoding=etf"));
+        let mut conn = GatewayContext::new(config);
+
+        let hello = etf::encode(&serde_json::json!({
+            "op": 10,
+            "d": {"heartbeat_interval": 41250},
+            "s": null,
+            "t": null,
+        }));
+        assert!(matches!(
+            conn.recv_binary(&hello).unwrap(),
+            Some(GatewayEvent::Hello(41250))
+        ));
+        assert_eq!(State::Identify, *conn.state());
+
+        let identify = etf::decode(&conn.send_etf().unwrap()).unwrap();
+        assert_eq!(2, identify["op"]);
+        assert_eq!("TOKEN", identify["d"]["token"]);
+        assert_eq!(serde_json::json!([0, 1]), identify["d"]["shard"]);
+
+        let resumed = etf::encode(&serde_json::json!({
+            "op": 0,
+            "d": {},
+            "s": 5,
+            "t": "RESUMED",
+        }));
+        assert!(matches!(
+            conn.recv_binary(&resumed).unwrap(),
+            Some(GatewayEvent::Dispatch(5, DispatchEvent::Resumed))
+        ));
+        assert_eq!(5, conn.seq);
+    }
+
+    #[test]
+    fn resume_session() {
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+        assert_eq!(None, conn.session());
+
+        conn.recv(&GatewayEvent::Hello(10));
+        let _identify = conn.send().unwrap();
+        conn.recv(&create_default_ready());
+        conn.recv(&GatewayEvent::Dispatch(7, DispatchEvent::Resumed));
+
+        let session = conn.session().unwrap();
+        assert_eq!("session_id", session.session_id);
+        assert_eq!(7, session.seq);
+        assert_eq!("resume_url", session.resume_url);
+
+        // continue the session in a new context
+        let mut conn = GatewayContext::resume(("TOKEN", Intents::empty()), session.clone());
+        assert_eq!(Some(session), conn.session());
+        assert!(conn.should_reconnect());
+
+        conn.socket_connected();
+        conn.recv(&GatewayEvent::Hello(10));
+        assert_eq!(State::Replaying, *conn.state());
+
+        let resume = match conn.send() {
+            Some(GatewayCommand::Resume(resume)) => resume,
+            cmd => panic!("expected GatewayCommand::Resume, got {:?}", cmd),
+        };
+        assert_eq!("session_id", resume.d.session_id);
+        assert_eq!(7, resume.d.seq);
+        assert_eq!("TOKEN", resume.d.token);
+    }
+
+    #[test]
+    fn transitions() {
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+        assert_eq!(None, conn.poll_transition());
+
+        conn.recv(&GatewayEvent::Hello(10));
+        let _identify = conn.send().unwrap();
+        conn.recv(&create_default_ready());
+        conn.recv(&GatewayEvent::Reconnect);
+        conn.recv(&GatewayEvent::Hello(10));
+        conn.recv(&GatewayEvent::Dispatch(1, DispatchEvent::Resumed));
+        conn.recv_close_code(4014u16);
+
+        let transitions: Vec<_> = std::iter::from_fn(|| conn.poll_transition())
+            .map(|t| (t.from, t.to, t.cause))
+            .collect();
+        assert_eq!(
+            vec![
+                (State::Closed, State::Identify, TransitionCause::Hello),
+                (State::Identify, State::Ready, TransitionCause::Ready),
+                (
+                    State::Ready,
+                    State::Resume,
+                    TransitionCause::ReconnectRequested
+                ),
+                (State::Resume, State::Replaying, TransitionCause::Hello),
+                (State::Replaying, State::Ready, TransitionCause::Resumed),
+                (
+                    State::Ready,
+                    State::Failed(CloseCode::DisallowedIntents),
+                    TransitionCause::Closed(CloseCode::DisallowedIntents)
+                ),
+            ],
+            transitions
+        );
+    }
+
+    #[test]
+    fn transitions_bounded() {
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+        conn.recv(&GatewayEvent::Hello(10));
+        let _identify = conn.send().unwrap();
+        conn.recv(&create_default_ready());
+
+        for _ in 0..TRANSITION_QUEUE_SIZE {
+            conn.recv_close_code(4000u16);
+            conn.recv(&GatewayEvent::Hello(10));
+        }
+        assert_eq!(TRANSITION_QUEUE_SIZE, conn.transitions.len());
+
+        // the oldest transitions were dropped
+        let transition = conn.poll_transition().unwrap();
+        assert_eq!(State::Replaying, transition.from);
+        assert_eq!(State::Resume, transition.to);
+    }
+
+    #[test]
+    fn event_queue() {
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+        conn.queue_events(true);
+
+        conn.recv_event(GatewayEvent::Hello(10));
+        let _identify = conn.send().unwrap();
+        conn.recv_event(create_default_ready());
+        conn.recv_event(GatewayEvent::Reconnect);
+        conn.recv_close_code(1000u16);
+        conn.recv_event(GatewayEvent::Hello(10));
+        conn.recv_event(GatewayEvent::Dispatch(2, DispatchEvent::Resumed));
+
+        let events: Vec<_> = std::iter::from_fn(|| conn.poll_event()).collect();
+        assert_eq!(11, events.len());
+        assert_eq!(Event::GatewayHello(10), events[0]);
+        assert_eq!(
+            Event::ShardIdentifying(Identifying {
+                shard_id: 0,
+                shard_total: 1
+            }),
+            events[1]
+        );
+        assert!(matches!(events[2], Event::Ready(_)));
+        assert_eq!(
+            Event::ShardConnected(Connected {
+                heartbeat_interval: 10,
+                shard_id: 0
+            }),
+            events[3]
+        );
+        assert_eq!(Event::GatewayReconnect, events[4]);
+        assert_eq!(
+            Event::ShardReconnecting(Reconnecting { shard_id: 0 }),
+            events[5]
+        );
+        assert_eq!(
+            Event::ShardDisconnected(Disconnected {
+                code: Some(1000),
+                reason: None,
+                shard_id: 0
+            }),
+            events[6]
+        );
+        assert_eq!(Event::GatewayHello(10), events[7]);
+        assert_eq!(
+            Event::ShardResuming(Resuming {
+                seq: 0,
+                shard_id: 0
+            }),
+            events[8]
+        );
+        assert_eq!(Event::Resumed, events[9]);
+        assert!(matches!(events[10], Event::ShardConnected(_)));
+    }
+
+    #[test]
+    fn event_queue_disabled() {
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+
+        for _ in 0..100 {
+            conn.recv(&GatewayEvent::Hello(10));
+            let _identify = conn.send();
+            conn.recv(&create_default_ready());
+            conn.recv(&GatewayEvent::Reconnect);
+            conn.recv_close_code(1000u16);
+        }
+        conn.recv_event(GatewayEvent::HeartbeatAck);
+
+        // events are only queued if the application polls them
+        assert!(conn.recv_queue.is_empty());
+        assert_eq!(None, conn.poll_event());
+    }
+
+    #[test]
+    fn zombie_transition() {
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+        conn.recv(&GatewayEvent::Hello(10));
+        conn.queue_heartbeat();
+        conn.queue_heartbeat();
+
+        let transition = std::iter::from_fn(|| conn.poll_transition())
+            .last()
+            .unwrap();
+        assert_eq!(State::Reconnect, transition.to);
+        assert_eq!(TransitionCause::Zombie, transition.cause);
+        assert!(!transition.is_connected());
+
+        // repeated causes without a state change are not reported
+        conn.recv(&GatewayEvent::InvalidateSession(false));
+        assert_eq!(None, conn.poll_transition());
+    }
+
+    #[test]
+    fn close_before_ready() {
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+        conn.recv(&GatewayEvent::Hello(10));
+        assert!(matches!(conn.send(), Some(GatewayCommand::Identify(_))));
+
+        // there is no session to resume yet
+        conn.recv_close_code(4000u16);
+        assert_eq!(State::Reconnect, *conn.state());
+        conn.recv(&GatewayEvent::Hello(10));
+        assert!(matches!(conn.send(), Some(GatewayCommand::Identify(_))));
+
+        conn.recv(&GatewayEvent::Reconnect);
+        assert_eq!(State::Reconnect, *conn.state());
+        conn.recv(&GatewayEvent::Hello(10));
+        assert!(matches!(conn.send(), Some(GatewayCommand::Identify(_))));
+    }
+
+    #[test]
+    fn close_code_clears_timeout() {
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+        conn.recv(&GatewayEvent::Hello(10));
+        assert!(conn.poll_timeout().is_some());
+
+        conn.recv_close_code(4000u16);
+        assert_eq!(None, conn.poll_timeout());
+    }
+
+    #[test]
+    fn reconnect_timeout() {
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+        conn.recv(&GatewayEvent::Hello(10));
+        let _identify = conn.send().unwrap();
+
+        // the deadline of the backoff replaces the heartbeat timer
+        conn.recv(&GatewayEvent::InvalidateSession(false));
+        assert!(conn.should_reconnect());
+        let deadline = conn.reconnect_not_before().unwrap();
+        assert_eq!(Some(deadline), conn.poll_timeout());
+
+        conn.socket_connected();
+        conn.recv(&GatewayEvent::Hello(10));
+        assert_eq!(None, conn.reconnect_not_before());
+        assert!(conn.poll_timeout().unwrap() < deadline);
+    }
+
+    #[test]
+    fn close_code_reidentify() {
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+        conn.recv(&GatewayEvent::Hello(10));
+        let _identify = conn.send().unwrap();
+        conn.recv(&create_default_ready());
+
+        // resumable close codes keep the session
+        conn.recv_close_code(4000u16);
+        assert_eq!(State::Resume, *conn.state());
+        assert!(conn.session().is_some());
+        conn.recv(&GatewayEvent::Hello(10));
+        assert!(matches!(conn.send(), Some(GatewayCommand::Resume(_))));
+        conn.recv(&GatewayEvent::Dispatch(1, DispatchEvent::Resumed));
+
+        // an invalid sequence number requires a new session
+        conn.recv_close_code(4007u16);
+        assert_eq!(State::Reconnect, *conn.state());
+        assert_eq!(None, conn.session());
+        assert!(conn.should_reconnect());
+        conn.recv(&GatewayEvent::Hello(10));
+        assert!(matches!(conn.send(), Some(GatewayCommand::Identify(_))));
+    }
+
+    #[test]
+    fn close() {
+        let mut conn = GatewayContext::new(("TOKEN", Intents::empty()));
+        conn.recv(&GatewayEvent::Hello(10));
+        let _identify = conn.send().unwrap();
+        conn.recv(&create_default_ready());
+        conn.enqueue_command(GatewayCommand::Heartbeat(Heartbeat::new(0)));
+
+        let session = conn.session();
+        assert_eq!(4000, conn.close(true));
+        assert_eq!(State::Closed, *conn.state());
+        assert_eq!(session, conn.session());
+        assert!(!conn.should_reconnect());
+        assert_eq!(None, conn.poll_timeout());
+        assert_eq!(None, conn.send());
+
+        let mut conn = GatewayContext::resume(("TOKEN", Intents::empty()), session.unwrap());
+        assert_eq!(1000, conn.close(false));
+        assert_eq!(None, conn.session());
+        let transition = std::iter::from_fn(|| conn.poll_transition()).last();
+        assert_eq!(Some(TransitionCause::Shutdown), transition.map(|t| t.cause));
+    }
 }
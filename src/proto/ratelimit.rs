@@ -0,0 +1,160 @@
+//! Rate limiting of outgoing gateway commands
+//!
+//! The gateway allows a client to send 120 commands every 60 seconds per connection.
+//! A part of this budget is reserved for heartbeats so that they are never delayed by other
+//! commands.
+//!
+//! <https://discord.com/developers/docs/topics/gateway#rate-limiting>
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Number of commands that can be sent during one [`RESET_PERIOD`]
+pub(crate) const COMMANDS_PER_RESET: u32 = 120;
+
+/// Length of the rate limit window
+pub(crate) const RESET_PERIOD: Duration = Duration::from_secs(60);
+
+/// Upper bound of the commands reserved for heartbeats, so that regular commands are never starved
+pub(crate) const MAX_RESERVED: u32 = COMMANDS_PER_RESET / 4;
+
+/// Sliding window rate limiter for outgoing commands
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    /// points in time at which the commands of the current window have been sent
+    sent: VecDeque<Instant>,
+    /// number of commands that are reserved for priority commands (e.g. heartbeats)
+    reserved: u32,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(Duration::ZERO)
+    }
+}
+
+impl RateLimiter {
+    /// Create a new rate limiter for a connection with the given heartbeat interval
+    ///
+    /// Reserves enough commands to send all heartbeats of a window plus one additional heartbeat
+    /// requested by the gateway, but at most [`MAX_RESERVED`].
+    pub fn new(heartbeat_interval: Duration) -> Self {
+        let reserved = if heartbeat_interval.is_zero() {
+            0
+        } else {
+            let (period, interval) = (RESET_PERIOD.as_nanos(), heartbeat_interval.as_nanos());
+            // rounded up without `u128::div_ceil`, which requires Rust 1.73
+            let heartbeats = period / interval + u128::from(period % interval != 0);
+            (heartbeats.min(u128::from(MAX_RESERVED)) as u32)
+                .saturating_add(1)
+                .min(MAX_RESERVED)
+        };
+
+        RateLimiter {
+            sent: VecDeque::with_capacity(COMMANDS_PER_RESET as usize),
+            reserved,
+        }
+    }
+
+    /// Returns the number of commands reserved for priority commands
+    pub fn reserved(&self) -> u32 {
+        self.reserved
+    }
+
+    fn limit(&self, priority: bool) -> usize {
+        if priority {
+            COMMANDS_PER_RESET as usize
+        } else {
+            (COMMANDS_PER_RESET - self.reserved) as usize
+        }
+    }
+
+    /// Removes all sends that are no longer within the window
+    fn expire(&mut self, now: Instant) {
+        while let Some(sent) = self.sent.front() {
+            if now.saturating_duration_since(*sent) >= RESET_PERIOD {
+                self.sent.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Returns the point in time at which the next command can be sent
+    ///
+    /// Priority commands can make use of the reserved part of the budget.
+    /// The returned instant may be in the past if a command can be sent right away.
+    pub fn next_available(&self, priority: bool) -> Option<Instant> {
+        let limit = self.limit(priority);
+
+        if self.sent.len() < limit {
+            // still room in the window, the instant of the oldest send is the latest the
+            // command could have been sent
+            return self.sent.front().copied();
+        }
+
+        self.sent
+            .get(self.sent.len() - limit)
+            .map(|sent| *sent + RESET_PERIOD)
+    }
+
+    /// Tries to take one command from the budget.
+    ///
+    /// Returns `false` if the budget is exhausted and the command has to be held back.
+    pub fn acquire(&mut self, now: Instant, priority: bool) -> bool {
+        self.expire(now);
+
+        if self.sent.len() >= self.limit(priority) {
+            return false;
+        }
+
+        self.sent.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_heartbeats() {
+        assert_eq!(0, RateLimiter::new(Duration::ZERO).reserved());
+        assert_eq!(3, RateLimiter::new(Duration::from_millis(41250)).reserved());
+        assert_eq!(2, RateLimiter::new(Duration::from_secs(60)).reserved());
+        assert_eq!(
+            MAX_RESERVED,
+            RateLimiter::new(Duration::from_millis(1)).reserved()
+        );
+    }
+
+    #[test]
+    fn window() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(Duration::from_secs(30));
+        assert_eq!(3, limiter.reserved());
+        assert_eq!(None, limiter.next_available(false));
+
+        for i in 0..117 {
+            assert!(limiter.acquire(start + Duration::from_millis(i), false));
+        }
+
+        // regular commands are held back, priority commands can still be sent
+        assert!(!limiter.acquire(start, false));
+        assert_eq!(Some(start + RESET_PERIOD), limiter.next_available(false));
+        assert!(limiter.acquire(start, true));
+        assert!(limiter.acquire(start, true));
+        assert!(limiter.acquire(start, true));
+        assert!(!limiter.acquire(start, true));
+
+        // the oldest command leaves the window
+        assert!(!limiter.acquire(start + RESET_PERIOD - Duration::from_millis(1), false));
+        assert!(limiter.acquire(start + RESET_PERIOD, true));
+        assert!(!limiter.acquire(start + RESET_PERIOD, false));
+
+        let later = start + RESET_PERIOD + Duration::from_millis(5);
+        assert!(limiter.acquire(later, false));
+    }
+}
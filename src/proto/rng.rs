@@ -0,0 +1,68 @@
+//! Minimal pseudo random number generator
+//!
+//! Randomness is only needed for jitter, so a small [SplitMix64] generator is sufficient and avoids
+//! pulling in additional dependencies.
+//!
+//! [SplitMix64]: https://prng.di.unimi.it/splitmix64.c
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+#[derive(Debug, Clone)]
+pub(crate) struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Create a new generator from a fixed seed
+    pub(crate) fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    /// Create a new generator with a random seed
+    pub(crate) fn from_entropy() -> Self {
+        let seed = RandomState::new().build_hasher().finish();
+        Rng::new(seed)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in the range `[0, 1)`
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        // use the upper 53 bits to fill the mantissa
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deterministic() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+
+        for _ in 0..100 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+    }
+
+    #[test]
+    fn unit_range() {
+        let mut rng = Rng::new(0);
+
+        for _ in 0..1000 {
+            let n = rng.next_f64();
+            assert!((0.0..1.0).contains(&n));
+        }
+    }
+}
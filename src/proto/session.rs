@@ -0,0 +1,53 @@
+//! Resumable session state
+
+use serde::{Deserialize, Serialize};
+
+/// Data needed to resume a gateway session
+///
+/// The state can be exported from a [`GatewayContext`] and used to create a new context that
+/// resumes the session, e.g. after restarting the process.
+///
+/// <https://discord.com/developers/docs/topics/gateway#resuming>
+///
+/// [`GatewayContext`]: super::GatewayContext
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionState {
+    /// id of the session
+    pub session_id: String,
+    /// sequence number of the last received event
+    pub seq: u64,
+    /// url that has to be used to resume the session
+    pub resume_url: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_test::Token;
+
+    #[test]
+    fn serde() {
+        let session = SessionState {
+            session_id: "session_id".to_string(),
+            seq: 42,
+            resume_url: "wss://resume.discord.gg".to_string(),
+        };
+
+        serde_test::assert_tokens(
+            &session,
+            &[
+                Token::Struct {
+                    name: "SessionState",
+                    len: 3,
+                },
+                Token::Str("session_id"),
+                Token::Str("session_id"),
+                Token::Str("seq"),
+                Token::U64(42),
+                Token::Str("resume_url"),
+                Token::Str("wss://resume.discord.gg"),
+                Token::StructEnd,
+            ],
+        );
+    }
+}
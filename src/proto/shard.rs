@@ -0,0 +1,196 @@
+//! Sharding
+//!
+//! Bots in many guilds have to split their gateway connections into multiple shards. Events of a
+//! guild are always sent over the shard `(guild_id >> 22) % total`.
+//!
+//! <https://discord.com/developers/docs/topics/gateway#sharding>
+
+use crate::Error;
+use std::{convert::TryFrom, fmt::Display, ops::Range};
+use twilight_model::id::{marker::GuildMarker, Id};
+
+/// Identifier of a shard, consisting of the index of the shard and the total number of shards
+///
+/// The index is always lower than the total.
+///
+/// # Example
+/// ```
+/// # use discord::proto::ShardId;
+/// let shard = ShardId::new(3, 4).unwrap();
+/// assert_eq!(3, shard.index());
+/// assert_eq!(4, shard.total());
+///
+/// assert!(ShardId::new(4, 4).is_err());
+/// ```
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct ShardId {
+    index: u64,
+    total: u64,
+}
+
+impl ShardId {
+    /// The only shard of a bot that does not use sharding
+    pub const ONE: ShardId = ShardId { index: 0, total: 1 };
+
+    /// Create a new shard id
+    ///
+    /// Returns an error if the index is not lower than the total.
+    pub fn new(index: u64, total: u64) -> Result<Self, Error> {
+        if index >= total {
+            return Err(Error::InvalidShardId { index, total });
+        }
+
+        Ok(ShardId { index, total })
+    }
+
+    /// Returns the index of the shard
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    /// Returns the total number of shards
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Returns the shard that receives the events of a guild
+    ///
+    /// # Example
+    /// ```
+    /// # use discord::proto::ShardId;
+    /// # use twilight_model::id::Id;
+    /// let guild_id = Id::new(81384788765712384);
+    /// assert_eq!(ShardId::new(2, 16).unwrap(), ShardId::for_guild(guild_id, 16).unwrap());
+    /// ```
+    pub fn for_guild(guild_id: Id<GuildMarker>, total: u64) -> Result<Self, Error> {
+        if total == 0 {
+            return Err(Error::InvalidShardId { index: 0, total });
+        }
+
+        ShardId::new((guild_id.get() >> 22) % total, total)
+    }
+
+    /// Returns true if this shard receives the events of the guild
+    pub fn contains_guild(&self, guild_id: Id<GuildMarker>) -> bool {
+        (guild_id.get() >> 22) % self.total == self.index
+    }
+
+    /// Returns the shards with the indices in `range`
+    pub fn range(range: Range<u64>, total: u64) -> Result<Vec<Self>, Error> {
+        if range.end > total {
+            return Err(Error::InvalidShardId {
+                index: range.end - 1,
+                total,
+            });
+        }
+
+        Ok(range.map(|index| ShardId { index, total }).collect())
+    }
+
+    /// Returns the shards of one cluster when `total` shards are distributed evenly over
+    /// `clusters` clusters
+    ///
+    /// The first clusters receive one additional shard if the shards can not be distributed
+    /// evenly.
+    ///
+    /// # Example
+    /// ```
+    /// # use discord::proto::ShardId;
+    /// let shards = ShardId::cluster(1, 3, 10).unwrap();
+    /// let indices: Vec<_> = shards.iter().map(ShardId::index).collect();
+    /// assert_eq!(vec![4, 5, 6], indices);
+    /// ```
+    pub fn cluster(cluster: u64, clusters: u64, total: u64) -> Result<Vec<Self>, Error> {
+        if cluster >= clusters {
+            return Err(Error::InvalidCluster { cluster, clusters });
+        }
+
+        let size = total / clusters;
+        let remainder = total % clusters;
+        let start = cluster * size + cluster.min(remainder);
+        let end = start + size + u64::from(cluster < remainder);
+
+        ShardId::range(start..end, total)
+    }
+}
+
+impl Default for ShardId {
+    fn default() -> Self {
+        ShardId::ONE
+    }
+}
+
+impl Display for ShardId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}, {}]", self.index, self.total)
+    }
+}
+
+impl TryFrom<[u64; 2]> for ShardId {
+    type Error = Error;
+
+    fn try_from([index, total]: [u64; 2]) -> Result<Self, Self::Error> {
+        ShardId::new(index, total)
+    }
+}
+
+impl From<ShardId> for [u64; 2] {
+    fn from(shard: ShardId) -> Self {
+        [shard.index, shard.total]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validation() {
+        assert!(ShardId::new(0, 1).is_ok());
+        assert!(ShardId::new(0, 0).is_err());
+        assert!(ShardId::new(2, 1).is_err());
+        assert!(ShardId::try_from([1, 1]).is_err());
+        assert_eq!([1, 2], <[u64; 2]>::from(ShardId::try_from([1, 2]).unwrap()));
+        assert!(ShardId::for_guild(Id::new(1), 0).is_err());
+    }
+
+    #[test]
+    fn guild_routing() {
+        let guild_id = Id::new(41771983423143937);
+        let shard = ShardId::for_guild(guild_id, 4).unwrap();
+        assert_eq!(((41771983423143937u64 >> 22) % 4), shard.index());
+        assert!(shard.contains_guild(guild_id));
+        assert!(ShardId::ONE.contains_guild(guild_id));
+
+        let others = ShardId::range(0..4, 4).unwrap();
+        assert_eq!(
+            1,
+            others.iter().filter(|s| s.contains_guild(guild_id)).count()
+        );
+    }
+
+    #[test]
+    fn clusters() {
+        let shards: Vec<_> = (0..3)
+            .map(|cluster| ShardId::cluster(cluster, 3, 10).unwrap())
+            .collect();
+        assert_eq!(
+            vec![4, 3, 3],
+            shards.iter().map(Vec::len).collect::<Vec<_>>()
+        );
+
+        // every shard is part of exactly one cluster
+        let all: Vec<_> = shards.into_iter().flatten().collect();
+        assert_eq!(ShardId::range(0..10, 10).unwrap(), all);
+
+        assert!(matches!(
+            ShardId::cluster(3, 3, 10),
+            Err(Error::InvalidCluster {
+                cluster: 3,
+                clusters: 3
+            })
+        ));
+        assert!(ShardId::range(0..11, 10).is_err());
+        assert_eq!(Vec::<ShardId>::new(), ShardId::cluster(2, 3, 2).unwrap());
+    }
+}
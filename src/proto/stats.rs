@@ -0,0 +1,180 @@
+//! Connection statistics
+
+use super::GatewayCommand;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Number of heartbeat round trip times that are kept for calculating the average and percentiles
+const LATENCY_SAMPLES: usize = 64;
+
+/// Statistics of a gateway connection
+///
+/// The counters are kept over all connections of the context, so they are not reset on reconnects.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// number of heartbeats sent to the gateway
+    pub heartbeats_sent: u64,
+    /// number of heartbeat acknowledgements received
+    pub heartbeat_acks: u64,
+    /// number of connections to the gateway (counted by received `Hello` events)
+    pub connections: u64,
+    /// number of times the socket reconnected to the gateway
+    pub reconnects: u64,
+    /// number of resume commands sent
+    pub resumes: u64,
+    /// number of identify commands sent
+    pub identifies: u64,
+    /// number of dispatch events received
+    pub dispatches: u64,
+    /// number of times the sequence number of a dispatch event skipped ahead
+    pub sequence_gaps: u64,
+    /// number of dispatch events that were skipped by sequence gaps
+    pub missed_events: u64,
+    /// number of dispatch events with a sequence number that was not greater than the last one
+    pub sequence_regressions: u64,
+    latencies: VecDeque<Duration>,
+    heartbeat_sent_at: Option<Instant>,
+}
+
+impl Stats {
+    /// Returns the round trip time of the last acknowledged heartbeat
+    pub fn latency(&self) -> Option<Duration> {
+        self.latencies.back().copied()
+    }
+
+    /// Returns the average round trip time of the recent heartbeats
+    pub fn average_latency(&self) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+
+        let sum: Duration = self.latencies.iter().sum();
+        Some(sum / self.latencies.len() as u32)
+    }
+
+    /// Returns the percentile of the recent heartbeat round trip times
+    ///
+    /// `percentile` is clamped to the range `0.0..=100.0`.
+    ///
+    /// # Example
+    /// ```
+    /// # use discord::{proto::GatewayContext, model::gateway::Intents};
+    /// # let ctx = GatewayContext::new(("TOKEN", Intents::empty()));
+    /// let p99 = ctx.stats().latency_percentile(99.0);
+    /// ```
+    pub fn latency_percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+
+        let mut sorted: Vec<_> = self.latencies.iter().copied().collect();
+        sorted.sort_unstable();
+
+        // nearest rank method
+        let percentile = percentile.clamp(0.0, 100.0);
+        let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+        sorted.get(rank.saturating_sub(1)).copied()
+    }
+
+    /// Returns the recent heartbeat round trip times, oldest first
+    pub fn latencies(&self) -> impl Iterator<Item = Duration> + '_ {
+        self.latencies.iter().copied()
+    }
+
+    pub(crate) fn record_sent(&mut self, cmd: &GatewayCommand, now: Instant) {
+        match cmd {
+            GatewayCommand::Heartbeat(_) => {
+                self.heartbeats_sent += 1;
+                self.heartbeat_sent_at = Some(now);
+            }
+            GatewayCommand::Identify(_) => self.identifies += 1,
+            GatewayCommand::Resume(_) => self.resumes += 1,
+            _ => {}
+        }
+    }
+
+    pub(crate) fn record_ack(&mut self, now: Instant) {
+        self.heartbeat_acks += 1;
+
+        if let Some(sent_at) = self.heartbeat_sent_at.take() {
+            if self.latencies.len() == LATENCY_SAMPLES {
+                self.latencies.pop_front();
+            }
+            self.latencies
+                .push_back(now.saturating_duration_since(sent_at));
+        }
+    }
+
+    pub(crate) fn record_connect(&mut self) {
+        if self.connections > 0 {
+            self.reconnects += 1;
+        }
+        self.connections += 1;
+        // an ack for a heartbeat of the previous connection will never arrive
+        self.heartbeat_sent_at = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use twilight_model::gateway::payload::outgoing::Heartbeat;
+
+    fn record_rtt(stats: &mut Stats, start: Instant, rtt: u64) {
+        stats.record_sent(&GatewayCommand::Heartbeat(Heartbeat::new(0)), start);
+        stats.record_ack(start + Duration::from_millis(rtt));
+    }
+
+    #[test]
+    fn latency() {
+        let start = Instant::now();
+        let mut stats = Stats::default();
+        assert_eq!(None, stats.latency());
+        assert_eq!(None, stats.average_latency());
+        assert_eq!(None, stats.latency_percentile(50.0));
+
+        for rtt in [40, 10, 30, 20, 100] {
+            record_rtt(&mut stats, start, rtt);
+        }
+
+        assert_eq!(5, stats.heartbeats_sent);
+        assert_eq!(5, stats.heartbeat_acks);
+        assert_eq!(Some(Duration::from_millis(100)), stats.latency());
+        assert_eq!(Some(Duration::from_millis(40)), stats.average_latency());
+        assert_eq!(
+            Some(Duration::from_millis(30)),
+            stats.latency_percentile(50.0)
+        );
+        assert_eq!(
+            Some(Duration::from_millis(100)),
+            stats.latency_percentile(99.0)
+        );
+        assert_eq!(
+            Some(Duration::from_millis(10)),
+            stats.latency_percentile(0.0)
+        );
+    }
+
+    #[test]
+    fn unsolicited_ack() {
+        let mut stats = Stats::default();
+        stats.record_ack(Instant::now());
+        assert_eq!(1, stats.heartbeat_acks);
+        assert_eq!(None, stats.latency());
+    }
+
+    #[test]
+    fn sample_window() {
+        let start = Instant::now();
+        let mut stats = Stats::default();
+
+        for rtt in 0..(LATENCY_SAMPLES as u64 + 10) {
+            record_rtt(&mut stats, start, rtt);
+        }
+
+        assert_eq!(LATENCY_SAMPLES, stats.latencies().count());
+        assert_eq!(Some(Duration::from_millis(10)), stats.latencies().next());
+    }
+}
@@ -0,0 +1,55 @@
+//! State transitions of the gateway context
+
+use super::State;
+use crate::error::CloseCode;
+
+/// A change of the [`State`] of a [`GatewayContext`]
+///
+/// Transitions are queued by the context and can be drained with [`poll_transition()`].
+///
+/// [`GatewayContext`]: super::GatewayContext
+/// [`poll_transition()`]: super::GatewayContext::poll_transition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transition {
+    /// state before the transition
+    pub from: State,
+    /// state after the transition
+    pub to: State,
+    /// what caused the transition
+    pub cause: TransitionCause,
+}
+
+/// The cause of a [`Transition`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransitionCause {
+    /// The gateway sent `Hello` on a new connection and the context started to identify or resume
+    Hello,
+    /// The session is ready after identifying
+    Ready,
+    /// The session finished resuming
+    Resumed,
+    /// The gateway invalidated the session, the flag indicates whether it can be resumed
+    InvalidSession(bool),
+    /// The gateway requested a reconnect
+    ReconnectRequested,
+    /// The gateway closed the connection with a close code
+    Closed(CloseCode),
+    /// The gateway did not acknowledge the last heartbeat
+    Zombie,
+    /// The socket could not be reconnected within the maximum number of attempts
+    ConnectFailed,
+    /// The application closed the connection
+    Shutdown,
+}
+
+impl Transition {
+    /// Returns true if the session became ready, either after identifying or resuming
+    pub fn is_connected(&self) -> bool {
+        self.to == State::Ready
+    }
+
+    /// Returns true if the connection failed and will not be reconnected
+    pub fn is_failed(&self) -> bool {
+        matches!(self.to, State::Failed(_))
+    }
+}